@@ -0,0 +1,95 @@
+use git2::{Oid, ErrorCode, ErrorClass};
+use crate::{Repo, GitmapError, CommitInfo};
+
+/// Notes reference holding commit annotations. Include it in push and fetch
+/// refspecs to share annotations.
+pub(crate) const NOTES_REF: &str = "refs/notes/gitmap";
+
+/// Commit annotations as name and value pairs.
+pub type Annotations = Vec<(String, Vec<u8>)>;
+
+/// Commit annotation functions.
+impl Repo {
+
+    /// Commits data and attaches the annotations to the new commit as a git
+    /// note so they can be read without parsing the message. Names must be
+    /// non-empty and free of whitespace. Returns the new commit ID.
+    pub fn commit_with_annotations(&self, message: &str, annotations: &[(&str, &[u8])]) -> Result<Oid, GitmapError> {
+        for (name, _) in annotations {
+            if name.is_empty() || name.chars().any(char::is_whitespace) {
+                return Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, format!("invalid annotation name `{}`", name)));
+            }
+        }
+        let id = self.commit(message)?;
+        if !annotations.is_empty() {
+            let sig = self.signature()?;
+            self.repo.note(&sig, &sig, Some(NOTES_REF), id, &encode_annotations(annotations), true)?;
+        }
+        Ok(id)
+    }
+
+    /// Returns annotations attached to the commit, empty when there are none.
+    pub fn commit_annotations(&self, commit: Oid) -> Result<Annotations, GitmapError> {
+        self.repo.find_commit(commit)?;
+        match self.repo.find_note(Some(NOTES_REF), commit) {
+            Ok(note) => Ok(decode_annotations(note.message().unwrap_or(""))),
+            Err(err) if err.code() == ErrorCode::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// List commits of the working branch together with their annotations,
+    /// newest first.
+    pub fn log_with_annotations(&self) -> Vec<(CommitInfo, Annotations)> {
+        self.log().into_iter().map(|info| {
+            let annotations = self.commit_annotations(info.oid).unwrap_or_default();
+            (info, annotations)
+        }).collect()
+    }
+}
+
+/// Serializes annotations as lines of a name and a hex encoded value.
+fn encode_annotations(annotations: &[(&str, &[u8])]) -> String {
+    annotations.iter().map(|(name, value)| {
+        let value: String = value.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{} {}\n", name, value)
+    }).collect()
+}
+
+/// Parses annotations serialized with `encode_annotations`.
+fn decode_annotations(data: &str) -> Annotations {
+    data.lines().filter_map(|line| {
+        let (name, value) = line.split_once(' ')?;
+        let value = (0..value.len()).step_by(2)
+            .map(|i| value.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+            .collect::<Option<Vec<u8>>>()?;
+        Some((name.to_string(), value))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn annotates_commits() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "".as_bytes()).unwrap();
+        let first = repo.commit("plain").unwrap();
+        let second = repo.commit_with_annotations("annotated", &[("deploy", "42".as_bytes()), ("raw", &[0, 255, 10])]).unwrap();
+        assert_eq!(repo.commit_annotations(first).unwrap(), Vec::new());
+        assert_eq!(repo.commit_annotations(second).unwrap(), vec![
+            ("deploy".to_string(), "42".as_bytes().to_vec()),
+            ("raw".to_string(), vec![0, 255, 10]),
+        ]);
+        assert_eq!(repo.commit_annotations(Oid::zero()).is_err(), true);
+        assert_eq!(repo.commit_with_annotations("", &[("bad name", &[])]).is_err(), true);
+        assert_eq!(repo.log().len(), 2);
+        let log = repo.log_with_annotations();
+        assert_eq!(log[0].0.message, "annotated");
+        assert_eq!(log[0].1.len(), 2);
+        assert_eq!(log[1].1, Vec::new());
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use git2::{Oid};
+use crate::{Repo, GitmapError, check_name};
+
+/// Set of key changes committed at once. Changes are kept in memory and the
+/// repository is only touched by `commit`, so dropping a batch discards it.
+pub struct Batch<'a> {
+    repo: &'a mut Repo,
+    changes: BTreeMap<String, Option<Oid>>,
+}
+
+/// Options of a `BulkLoader`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BulkOptions {
+    /// Number of entries committed at once, 0 committing only on `finish`.
+    pub commit_every: usize,
+    /// Commit message where `{batch}` is replaced with the batch number and
+    /// `{start}` and `{end}` with the numbers of its first and last entry,
+    /// all counted from 1.
+    pub message_template: String,
+}
+
+/// Result of a bulk load.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BulkSummary {
+    /// IDs of the created commits in order.
+    pub commits: Vec<Oid>,
+    /// Number of committed entries.
+    pub keys_loaded: usize,
+}
+
+/// Error of a bulk load. Batches committed before the failure are kept.
+#[derive(Debug)]
+pub struct BulkError {
+    /// Cause of the failure.
+    pub error: GitmapError,
+    /// Keys of the failed batch which were not persisted.
+    pub unpersisted: Vec<String>,
+    /// Batches committed so far.
+    pub summary: BulkSummary,
+}
+
+/// Loads many keys committing them in batches of a fixed size, which keeps
+/// the staged tree small without creating a commit per key.
+pub struct BulkLoader<'a> {
+    repo: &'a mut Repo,
+    options: BulkOptions,
+    pending: Vec<(String, Oid)>,
+    summary: BulkSummary,
+    batches: usize,
+}
+
+/// Batch functions.
+impl Repo {
+
+    /// Starts a batch of changes applied on top of the staged keys and
+    /// committed with a single tree write. This is much faster than calling
+    /// `insert_key` in a loop when changing many keys.
+    pub fn batch(&mut self) -> Batch<'_> {
+        Batch { repo: self, changes: BTreeMap::new() }
+    }
+}
+
+impl<'a> Batch<'a> {
+
+    /// Adds a key to the batch. Only the value blob is written right away.
+    pub fn insert(&mut self, name: &str, value: &[u8]) -> Result<(), GitmapError> {
+        let name = self.repo.key_name(name).into_owned();
+        check_name(&name)?;
+        self.repo.check_value(&name, value)?;
+        let blob = self.repo.write_blob(value)?;
+        self.changes.insert(name, Some(blob));
+        Ok(())
+    }
+
+    /// Adds key removal to the batch.
+    pub fn remove(&mut self, name: &str) -> Result<(), GitmapError> {
+        let name = self.repo.key_name(name).into_owned();
+        check_name(&name)?;
+        self.changes.insert(name, None);
+        Ok(())
+    }
+
+    /// Writes the tree and commits it together with the staged keys. Returns
+    /// the new commit ID. The repository is left untouched on error.
+    pub fn commit(self, message: &str) -> Result<Oid, GitmapError> {
+        let changes: Vec<(&str, Option<Oid>)> = self.changes.iter().map(|(name, blob)| (name.as_str(), *blob)).collect();
+        let tree_id = self.repo.update_tree(Some(self.repo.current_tree_id()?), &changes)?;
+        let id = self.repo.commit_tree(tree_id, message, &self.repo.signature()?)?;
+        self.repo.set_staged_tree(Some(tree_id));
+        Ok(id)
+    }
+}
+
+impl<'a> BulkLoader<'a> {
+
+    /// Returns a loader committing to the working branch of the repository.
+    /// Entries are committed on top of the staged keys.
+    pub fn new(repo: &'a mut Repo, options: BulkOptions) -> Self {
+        Self { repo, options, pending: Vec::new(), summary: BulkSummary::default(), batches: 0 }
+    }
+
+    /// Adds a key and commits the batch once it holds `commit_every` entries.
+    /// On error the whole pending batch is discarded and reported.
+    pub fn push(&mut self, name: &str, value: &[u8]) -> Result<(), BulkError> {
+        let name = self.repo.key_name(name).into_owned();
+        let blob = check_name(&name)
+            .and_then(|_| self.repo.check_value(&name, value))
+            .and_then(|_| self.repo.write_blob(value));
+        match blob {
+            Ok(blob) => self.pending.push((name, blob)),
+            Err(error) => return Err(self.fail(error, Some(name))),
+        }
+        if self.pending.len() == self.options.commit_every {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Commits the remaining entries and returns the summary of the load.
+    pub fn finish(mut self) -> Result<BulkSummary, BulkError> {
+        if !self.pending.is_empty() {
+            self.flush()?;
+        }
+        Ok(self.summary)
+    }
+
+    /// Commits the pending entries as the next batch.
+    fn flush(&mut self) -> Result<(), BulkError> {
+        let start = self.summary.keys_loaded + 1;
+        let message = self.options.message_template
+            .replace("{batch}", &(self.batches + 1).to_string())
+            .replace("{start}", &start.to_string())
+            .replace("{end}", &(start + self.pending.len() - 1).to_string());
+        let changes: BTreeMap<&str, Option<Oid>> = self.pending.iter().map(|(name, blob)| (name.as_str(), Some(*blob))).collect();
+        let changes: Vec<(&str, Option<Oid>)> = changes.into_iter().collect();
+        let committed = self.repo.current_tree_id()
+            .and_then(|tree_id| self.repo.update_tree(Some(tree_id), &changes))
+            .and_then(|tree_id| Ok((tree_id, self.repo.commit_tree(tree_id, &message, &self.repo.signature()?)?)));
+        match committed {
+            Ok((tree_id, id)) => {
+                self.repo.set_staged_tree(Some(tree_id));
+                self.summary.commits.push(id);
+                self.summary.keys_loaded += self.pending.len();
+                self.batches += 1;
+                self.pending.clear();
+                Ok(())
+            },
+            Err(error) => Err(self.fail(error, None)),
+        }
+    }
+
+    /// Discards the pending batch and returns the error reporting its keys
+    /// together with the rejected one if any.
+    fn fail(&mut self, error: GitmapError, rejected: Option<String>) -> BulkError {
+        let mut unpersisted: Vec<String> = self.pending.drain(..).map(|(name, _)| name).chain(rejected).collect();
+        unpersisted.sort();
+        unpersisted.dedup();
+        BulkError { error, unpersisted, summary: self.summary.clone() }
+    }
+}
+
+impl fmt::Display for BulkError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} keys not persisted)", self.error, self.unpersisted.len())
+    }
+}
+
+impl std::error::Error for BulkError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+
+    /// Counts objects in the repository database.
+    fn count_objects(repo: &Repo) -> usize {
+        let mut count = 0;
+        repo.repo.odb().unwrap().foreach(|_| {
+            count += 1;
+            true
+        }).unwrap();
+        count
+    }
+
+    #[test]
+    fn commits_batch() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("old", "".as_bytes()).unwrap();
+        repo.commit("initial").unwrap();
+        repo.insert_key("staged", "".as_bytes()).unwrap();
+        let objects = count_objects(&repo);
+        let mut batch = repo.batch();
+        for i in 0..500 {
+            batch.insert(&format!("key{:03}", i), i.to_string().as_bytes()).unwrap();
+        }
+        batch.remove("old").unwrap();
+        batch.commit("import").unwrap();
+        assert_eq!(count_objects(&repo), objects + 500 + 2); // blobs, tree and commit
+        assert_eq!(repo.log().len(), 2);
+        assert_eq!(repo.len_committed(), 501);
+        assert_eq!(repo.key("key042").unwrap(), "42".as_bytes());
+        assert_eq!(repo.has_key("staged"), true);
+        assert_eq!(repo.has_key("old"), false);
+        assert_eq!(repo.changed(), false);
+    }
+
+    #[test]
+    fn discards_failed_batch() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "".as_bytes()).unwrap();
+        let tree_id = repo.tree_id;
+        let mut batch = repo.batch();
+        batch.insert("bar", "".as_bytes()).unwrap();
+        assert_eq!(batch.insert(".gitmap.aliases", "".as_bytes()).is_err(), true);
+        drop(batch);
+        let mut batch = repo.batch();
+        batch.insert("foo/bar", "".as_bytes()).unwrap();
+        assert_eq!(batch.commit("").is_err(), true);
+        assert_eq!(repo.tree_id, tree_id);
+        assert_eq!(repo.keys(), ["foo"]);
+        assert_eq!(repo.has_commits(), false);
+    }
+
+    #[test]
+    fn loads_keys_in_batches() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        let options = BulkOptions { commit_every: 2, message_template: "batch {batch}: {start}-{end}".to_string() };
+        let mut loader = BulkLoader::new(&mut repo, options);
+        for i in 0..5 {
+            loader.push(&format!("key{}", i), i.to_string().as_bytes()).unwrap();
+        }
+        let summary = loader.finish().unwrap();
+        assert_eq!(summary.commits.len(), 3);
+        assert_eq!(summary.keys_loaded, 5);
+        let messages: Vec<String> = repo.log().into_iter().map(|commit| commit.message).collect();
+        assert_eq!(messages, ["batch 3: 5-5", "batch 2: 3-4", "batch 1: 1-2"]);
+        assert_eq!(repo.len_committed(), 5);
+        assert_eq!(repo.changed(), false);
+    }
+
+    #[test]
+    fn reports_unpersisted_keys() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        let options = BulkOptions { commit_every: 3, message_template: "".to_string() };
+        let mut loader = BulkLoader::new(&mut repo, options);
+        for name in ["a", "b", "c", "d", "e"] {
+            loader.push(name, "".as_bytes()).unwrap();
+        }
+        let err = loader.push(".gitmap.aliases", "".as_bytes()).unwrap_err();
+        assert_eq!(err.unpersisted, [".gitmap.aliases", "d", "e"]);
+        assert_eq!(err.summary.keys_loaded, 3);
+        assert_eq!(loader.finish().unwrap().commits.len(), 1);
+        assert_eq!(repo.keys(), ["a", "b", "c"]);
+        assert_eq!(repo.log().len(), 1);
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::{BTreeMap, BTreeSet};
+use git2::{ErrorCode, ErrorClass, Oid};
+use crate::{Repo, GitmapError};
+
+/// First line of a replicated counter value.
+const COUNTER_HEADER: &str = "gitmap-counter 1";
+
+/// First line of a replicated set value.
+const SET_HEADER: &str = "gitmap-set 1";
+
+/// Counter stored as `<writer> <increments> <decrements>` lines below the
+/// header. Totals of a writer only grow so replicas merge by taking the
+/// larger totals of each writer.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Counter {
+    totals: BTreeMap<String, (u64, u64)>,
+}
+
+/// Observed-remove set stored as `+ <tag> <hex member>` lines for additions
+/// and `- <tag>` lines for removed additions below the header. Replicas merge
+/// by joining both, so an addition survives unless its tag was observed by a
+/// removal.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ReplicatedSet {
+    added: BTreeSet<(String, Vec<u8>)>,
+    removed: BTreeSet<String>,
+}
+
+/// Replicated value functions.
+impl Repo {
+
+    /// Sets the ID this replica records its counter and set changes under.
+    /// Every replica writing the same keys needs a distinct ID. Errors when
+    /// the ID is empty or contains whitespace.
+    pub fn set_writer_id(&mut self, id: &str) -> Result<(), GitmapError> {
+        if id.is_empty() || id.contains(char::is_whitespace) {
+            return Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, format!("invalid writer ID `{}`", id)));
+        }
+        self.writer_id = Some(id.to_string());
+        Ok(())
+    }
+
+    /// Stages adding `by`, which can be negative, to the counter key. Counter
+    /// keys written by different replicas are summed by `merge_branch`
+    /// instead of conflicting. Errors without a writer ID or when the key
+    /// holds another value.
+    pub fn increment_counter(&mut self, name: &str, by: i64) -> Result<(), GitmapError> {
+        let writer = self.writer()?;
+        let mut counter = self.counter(name)?;
+        let totals = counter.totals.entry(writer).or_default();
+        match by < 0 {
+            true => totals.1 = totals.1.saturating_add(by.unsigned_abs()),
+            false => totals.0 = totals.0.saturating_add(by as u64),
+        }
+        self.insert_key(name, counter.encode().as_bytes())
+    }
+
+    /// Reads the counter key including staged changes. A missing key counts
+    /// as 0. Errors when the key holds another value.
+    pub fn read_counter(&self, name: &str) -> Result<i64, GitmapError> {
+        Ok(self.counter(name)?.value())
+    }
+
+    /// Stages adding the member to the set key. Set keys written by different
+    /// replicas are joined by `merge_branch` instead of conflicting. Errors
+    /// without a writer ID or when the key holds another value.
+    pub fn add_to_set(&mut self, name: &str, member: &[u8]) -> Result<(), GitmapError> {
+        let writer = self.writer()?;
+        let mut set = self.replicated_set(name)?;
+        let prefix = format!("{}.", writer);
+        let seq = set.added.iter()
+            .filter_map(|(tag, _)| tag.strip_prefix(&prefix).and_then(|seq| seq.parse::<u64>().ok()))
+            .max()
+            .map_or(1, |seq| seq + 1);
+        set.added.insert((format!("{}{}", prefix, seq), member.to_vec()));
+        self.insert_key(name, set.encode().as_bytes())
+    }
+
+    /// Stages removing the member from the set key. Only additions seen by
+    /// this replica are removed, so a concurrent addition on another replica
+    /// survives the merge. Errors when the key holds another value.
+    pub fn remove_from_set(&mut self, name: &str, member: &[u8]) -> Result<(), GitmapError> {
+        let mut set = self.replicated_set(name)?;
+        let tags: Vec<String> = set.added.iter().filter(|(_, m)| m == member).map(|(tag, _)| tag.clone()).collect();
+        if tags.iter().all(|tag| set.removed.contains(tag)) {
+            return Ok(());
+        }
+        set.removed.extend(tags);
+        self.insert_key(name, set.encode().as_bytes())
+    }
+
+    /// Reads the sorted members of the set key including staged changes. A
+    /// missing key is an empty set. Errors when the key holds another value.
+    pub fn read_set(&self, name: &str) -> Result<Vec<Vec<u8>>, GitmapError> {
+        let ReplicatedSet { added, removed } = self.replicated_set(name)?;
+        let members: BTreeSet<Vec<u8>> = added.into_iter()
+            .filter(|(tag, _)| !removed.contains(tag))
+            .map(|(_, member)| member)
+            .collect();
+        Ok(members.into_iter().collect())
+    }
+
+    /// Merges two values of the same replicated type and writes the result.
+    /// Returns `None` when the values are not replicated values of one type.
+    pub(crate) fn merge_replicated(&self, ours: Oid, theirs: Oid) -> Result<Option<Oid>, GitmapError> {
+        let ours = self.repo.find_blob(ours)?;
+        let theirs = self.repo.find_blob(theirs)?;
+        let (ours, theirs) = match (std::str::from_utf8(ours.content()), std::str::from_utf8(theirs.content())) {
+            (Ok(ours), Ok(theirs)) => (ours.to_string(), theirs.to_string()),
+            _ => return Ok(None),
+        };
+        let merged = if let (Some(mut ours), Some(theirs)) = (Counter::decode(&ours), Counter::decode(&theirs)) {
+            for (writer, (inc, dec)) in theirs.totals {
+                let totals = ours.totals.entry(writer).or_default();
+                *totals = (totals.0.max(inc), totals.1.max(dec));
+            }
+            ours.encode()
+        } else if let (Some(mut ours), Some(theirs)) = (ReplicatedSet::decode(&ours), ReplicatedSet::decode(&theirs)) {
+            ours.added.extend(theirs.added);
+            ours.removed.extend(theirs.removed);
+            ours.encode()
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(self.repo.blob(merged.as_bytes())?))
+    }
+
+    /// Returns the writer ID or errors when it is not set.
+    fn writer(&self) -> Result<String, GitmapError> {
+        match &self.writer_id {
+            Some(id) => Ok(id.clone()),
+            None => Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, "writer ID is not set")),
+        }
+    }
+
+    /// Reads the counter key, a missing key being an empty counter.
+    fn counter(&self, name: &str) -> Result<Counter, GitmapError> {
+        match self.try_key(name)? {
+            Some(value) => std::str::from_utf8(&value).ok().and_then(Counter::decode).ok_or_else(|| invalid_value(name, "counter")),
+            None => Ok(Counter::default()),
+        }
+    }
+
+    /// Reads the set key, a missing key being an empty set.
+    fn replicated_set(&self, name: &str) -> Result<ReplicatedSet, GitmapError> {
+        match self.try_key(name)? {
+            Some(value) => std::str::from_utf8(&value).ok().and_then(ReplicatedSet::decode).ok_or_else(|| invalid_value(name, "set")),
+            None => Ok(ReplicatedSet::default()),
+        }
+    }
+}
+
+impl Counter {
+
+    /// Returns the sum of all increments minus all decrements.
+    fn value(&self) -> i64 {
+        let sum: i128 = self.totals.values().map(|(inc, dec)| *inc as i128 - *dec as i128).sum();
+        sum.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    /// Parses the stored value or returns `None` when it is not a counter.
+    fn decode(value: &str) -> Option<Self> {
+        let mut lines = value.lines();
+        if lines.next()? != COUNTER_HEADER {
+            return None;
+        }
+        let mut counter = Self::default();
+        for line in lines {
+            let mut fields = line.split(' ');
+            let (writer, inc, dec) = (fields.next()?, fields.next()?.parse().ok()?, fields.next()?.parse().ok()?);
+            if fields.next().is_some() {
+                return None;
+            }
+            counter.totals.insert(writer.to_string(), (inc, dec));
+        }
+        Some(counter)
+    }
+
+    /// Returns the stored value.
+    fn encode(&self) -> String {
+        let mut value = format!("{}\n", COUNTER_HEADER);
+        for (writer, (inc, dec)) in &self.totals {
+            value.push_str(&format!("{} {} {}\n", writer, inc, dec));
+        }
+        value
+    }
+}
+
+impl ReplicatedSet {
+
+    /// Parses the stored value or returns `None` when it is not a set.
+    fn decode(value: &str) -> Option<Self> {
+        let mut lines = value.lines();
+        if lines.next()? != SET_HEADER {
+            return None;
+        }
+        let mut set = Self::default();
+        for line in lines {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields[..] {
+                ["+", tag, member] => set.added.insert((tag.to_string(), decode_hex(member)?)),
+                ["-", tag] => set.removed.insert(tag.to_string()),
+                _ => return None,
+            };
+        }
+        Some(set)
+    }
+
+    /// Returns the stored value.
+    fn encode(&self) -> String {
+        let mut value = format!("{}\n", SET_HEADER);
+        for (tag, member) in &self.added {
+            value.push_str(&format!("+ {} {}\n", tag, member.iter().map(|b| format!("{:02x}", b)).collect::<String>()));
+        }
+        for tag in &self.removed {
+            value.push_str(&format!("- {}\n", tag));
+        }
+        value
+    }
+}
+
+/// Parses a lowercase hex string.
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len()).step_by(2).map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Returns the error of a key holding a value of another type.
+fn invalid_value(name: &str, kind: &str) -> GitmapError {
+    GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, format!("key `{}` does not hold a replicated {}", name, kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use crate::MergeOutcome;
+    use super::*;
+
+    /// Returns a repository with a committed counter and set and a `replica`
+    /// branch pointing at the same commit. Both branches write as `a`.
+    fn replicated_repo() -> Repo {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.set_writer_id("a").unwrap();
+        repo.increment_counter("hits", 1).unwrap();
+        repo.add_to_set("tags", "x".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.switch_branch("replica").unwrap();
+        repo.reset().unwrap();
+        repo
+    }
+
+    #[test]
+    fn stores_replicated_values() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.increment_counter("hits", 1).is_err(), true); // no writer ID
+        assert_eq!(repo.set_writer_id("a b").is_err(), true);
+        repo.set_writer_id("a").unwrap();
+        assert_eq!(repo.read_counter("hits").unwrap(), 0);
+        repo.increment_counter("hits", 5).unwrap();
+        repo.increment_counter("hits", -2).unwrap();
+        assert_eq!(repo.read_counter("hits").unwrap(), 3);
+        assert_eq!(repo.key("hits").unwrap(), "gitmap-counter 1\na 5 2\n".as_bytes());
+        repo.add_to_set("tags", "x".as_bytes()).unwrap();
+        repo.add_to_set("tags", "y".as_bytes()).unwrap();
+        repo.remove_from_set("tags", "x".as_bytes()).unwrap();
+        repo.remove_from_set("tags", "z".as_bytes()).unwrap();
+        assert_eq!(repo.read_set("tags").unwrap(), ["y".as_bytes()]);
+        assert_eq!(repo.key("tags").unwrap(), "gitmap-set 1\n+ a.1 78\n+ a.2 79\n- a.1\n".as_bytes());
+        repo.insert_key("plain", "1".as_bytes()).unwrap();
+        assert_eq!(repo.read_counter("plain").is_err(), true);
+        assert_eq!(repo.add_to_set("hits", "x".as_bytes()).is_err(), true);
+    }
+
+    #[test]
+    fn merges_replicated_values() {
+        let mut repo = replicated_repo();
+        repo.set_writer_id("b").unwrap();
+        repo.increment_counter("hits", 2).unwrap();
+        repo.add_to_set("tags", "y".as_bytes()).unwrap();
+        repo.remove_from_set("tags", "x".as_bytes()).unwrap();
+        repo.insert_key("plain", "b".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.switch_branch("master").unwrap();
+        repo.reset().unwrap();
+        repo.set_writer_id("a").unwrap();
+        repo.increment_counter("hits", 10).unwrap();
+        repo.add_to_set("tags", "x".as_bytes()).unwrap(); // concurrent addition survives
+        repo.add_to_set("tags", "z".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(matches!(repo.merge_branch("replica", "").unwrap(), MergeOutcome::Merged(_)), true);
+        assert_eq!(repo.read_counter("hits").unwrap(), 13);
+        assert_eq!(repo.read_set("tags").unwrap(), ["x".as_bytes(), "y".as_bytes(), "z".as_bytes()]);
+        assert_eq!(repo.key("plain").unwrap(), "b".as_bytes());
+    }
+
+    #[test]
+    fn reports_plain_conflicts() {
+        let mut repo = replicated_repo();
+        repo.insert_key("plain", "1".as_bytes()).unwrap();
+        repo.increment_counter("hits", 1).unwrap();
+        repo.commit("").unwrap();
+        repo.switch_branch("master").unwrap();
+        repo.reset().unwrap();
+        repo.insert_key("plain", "2".as_bytes()).unwrap();
+        repo.set_writer_id("b").unwrap();
+        repo.increment_counter("hits", 1).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.merge_branch("replica", "").unwrap(), MergeOutcome::Conflicts(vec!["plain".to_string()]));
+    }
+}
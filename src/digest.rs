@@ -0,0 +1,212 @@
+use git2::{Oid, ObjectType, TreeWalkMode, TreeWalkResult};
+use sha2::{Digest, Sha256};
+use crate::{Repo, GitmapError, is_meta};
+
+/// Commit message trailer holding the tree digest.
+const DIGEST_TRAILER: &str = "Gitmap-Tree-Digest: ";
+
+/// Repository config enabling tamper evidence for one-shot writes.
+pub(crate) const TAMPER_EVIDENCE_CONFIG: &str = "gitmap.tamperEvidence";
+
+/// Problem found while verifying tree digests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TamperFinding {
+    /// Commit carries no digest trailer.
+    MissingDigest(Oid),
+    /// Recorded digest differs from the digest of the commit tree.
+    DigestMismatch {
+        /// Commit ID.
+        commit: Oid,
+        /// Digest recorded in the commit message.
+        recorded: String,
+        /// Digest of the commit tree.
+        actual: String,
+    },
+}
+
+/// Tamper evidence functions.
+impl Repo {
+
+    /// Enables tamper evidence in which every commit message gets a
+    /// `Gitmap-Tree-Digest` trailer with the SHA-256 digest of the sorted key
+    /// and blob ID pairs of the committed tree. One-shot `Repo::write_key`
+    /// calls read the setting from the `gitmap.tamperEvidence` config instead.
+    pub fn set_tamper_evidence(&mut self, enabled: bool) {
+        self.tamper_evidence = enabled;
+    }
+
+    /// Recomputes tree digests of commits reachable from HEAD and reports
+    /// commits with a missing or mismatching trailer. The optional `(from, to)`
+    /// range limits the check to commits reachable from `to` but not `from`.
+    pub fn verify_tamper_evidence(&self, range: Option<(Oid, Oid)>) -> Result<Vec<TamperFinding>, GitmapError> {
+        let mut findings = Vec::new();
+        let mut walk = self.repo.revwalk()?;
+        match range {
+            Some((from, to)) => {
+                walk.push(to)?;
+                walk.hide(from)?;
+            },
+            None if self.has_commits() => walk.push(self.last_commit_id()?)?,
+            None => return Ok(findings),
+        }
+        for id in walk {
+            let commit = self.repo.find_commit(id?)?;
+            let message = String::from_utf8_lossy(commit.message_bytes()).to_string();
+            let recorded = match message.lines().rev().find_map(|l| l.strip_prefix(DIGEST_TRAILER)) {
+                Some(digest) => digest.trim().to_string(),
+                None => {
+                    findings.push(TamperFinding::MissingDigest(commit.id()));
+                    continue;
+                },
+            };
+            let actual = self.tree_digest(commit.tree_id())?;
+            if recorded != actual {
+                findings.push(TamperFinding::DigestMismatch { commit: commit.id(), recorded, actual });
+            }
+        }
+        Ok(findings)
+    }
+
+    /// Returns the SHA-256 fingerprint of the current content including staged
+    /// changes. Stores with identical keys and values have identical
+    /// fingerprints regardless of their history.
+    pub fn content_fingerprint(&self) -> Result<[u8; 32], GitmapError> {
+        match self.current_tree_id() {
+            Ok(tree_id) => self.tree_fingerprint(tree_id, false),
+            Err(GitmapError::NoCommits) => self.tree_fingerprint(self.empty_tree_id()?, false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns the content fingerprint at the tip of the provided branch.
+    pub fn branch_fingerprint(&self, name: &str) -> Result<[u8; 32], GitmapError> {
+        self.tree_fingerprint(self.branch_tree_id(name)?, false)
+    }
+
+    /// Returns the content fingerprint of the provided commit.
+    pub fn fingerprint_at(&self, commit: Oid) -> Result<[u8; 32], GitmapError> {
+        self.tree_fingerprint(self.repo.find_commit(commit)?.tree_id(), false)
+    }
+
+    /// Appends the digest trailer to the message when tamper evidence is on.
+    pub(crate) fn digest_message(&self, message: &str, tree_id: Oid) -> Result<String, GitmapError> {
+        if !self.tamper_evidence {
+            return Ok(message.to_string());
+        }
+        let trailer = format!("{}{}", DIGEST_TRAILER, self.tree_digest(tree_id)?);
+        match message.trim_end() {
+            "" => Ok(trailer),
+            message => Ok(format!("{}\n\n{}", message, trailer)),
+        }
+    }
+
+    /// Returns the hex encoded fingerprint of the provided tree.
+    pub(crate) fn tree_digest(&self, tree_id: Oid) -> Result<String, GitmapError> {
+        let fingerprint = self.tree_fingerprint(tree_id, true)?;
+        Ok(fingerprint.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// Computes the SHA-256 digest of the sorted `(key, blob ID)` pairs of the
+    /// provided tree. Metadata entries like aliases are only included when
+    /// `with_meta` is set.
+    fn tree_fingerprint(&self, tree_id: Oid, with_meta: bool) -> Result<[u8; 32], GitmapError> {
+        let mut entries = Vec::new();
+        self.repo.find_tree(tree_id)?.walk(TreeWalkMode::PreOrder, |root, entry| {
+            let name = entry.name().unwrap_or("");
+            if root.is_empty() && is_meta(name) && !with_meta {
+                return TreeWalkResult::Skip;
+            }
+            if entry.kind() == Some(ObjectType::Blob) {
+                entries.push((format!("{}{}", root, name), entry.id()));
+            }
+            TreeWalkResult::Ok
+        })?;
+        entries.sort();
+        let mut hasher = Sha256::new();
+        for (key, id) in entries {
+            hasher.update(key.as_bytes());
+            hasher.update([0]);
+            hasher.update(id.as_bytes());
+        }
+        Ok(hasher.finalize().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn verifies_tamper_evidence() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.verify_tamper_evidence(None).unwrap().len(), 0);
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("plain").unwrap();
+        let plain = repo.last_commit_id().unwrap();
+        repo.set_tamper_evidence(true);
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.commit("signed").unwrap();
+        let signed = repo.last_commit_id().unwrap();
+        let message = repo.log()[0].message.clone();
+        assert_eq!(message.starts_with("signed\n\nGitmap-Tree-Digest: "), true);
+        assert_eq!(repo.verify_tamper_evidence(Some((plain, signed))).unwrap().len(), 0);
+        assert_eq!(repo.verify_tamper_evidence(None).unwrap(), [TamperFinding::MissingDigest(plain)]);
+        // forge a commit reusing the trailer of another tree
+        repo.insert_key("foo", "3".as_bytes()).unwrap();
+        repo.set_tamper_evidence(false);
+        repo.commit(&message).unwrap();
+        let forged = repo.last_commit_id().unwrap();
+        let findings = repo.verify_tamper_evidence(Some((signed, forged))).unwrap();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(matches!(findings[0], TamperFinding::DigestMismatch { commit, .. } if commit == forged), true);
+    }
+    #[test]
+    fn signs_one_shot_writes() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let repo = Repo::init(&path).unwrap();
+        let plain = Repo::write_key(&path, None, "foo", "1".as_bytes(), "").unwrap();
+        repo.repo.config().unwrap().set_bool(TAMPER_EVIDENCE_CONFIG, true).unwrap();
+        repo.repo.branch("staging", &repo.repo.find_commit(plain).unwrap(), false).unwrap();
+        let signed = Repo::write_key(&path, None, "foo", "2".as_bytes(), "").unwrap();
+        Repo::write_key(&path, Some("staging"), "bar", "1".as_bytes(), "").unwrap();
+        assert_eq!(repo.verify_tamper_evidence(None).unwrap(), [TamperFinding::MissingDigest(plain)]);
+        assert_eq!(repo.verify_tamper_evidence(Some((plain, signed))).unwrap().len(), 0);
+        assert_eq!(repo.repo.find_branch("staging", git2::BranchType::Local).unwrap().get().peel_to_commit().unwrap().message().unwrap().starts_with(DIGEST_TRAILER), true);
+    }
+
+    #[test]
+    fn fingerprints_content() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut a = Repo::init(&path).unwrap();
+        let empty = a.content_fingerprint().unwrap();
+        a.insert_key("foo", "1".as_bytes()).unwrap();
+        let first = a.commit("").unwrap();
+        a.insert_key("dir/bar", "2".as_bytes()).unwrap();
+        a.commit("").unwrap();
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut b = Repo::init(&path).unwrap();
+        b.set_signature("other", "other@localhost");
+        b.insert_key("dir/bar", "2".as_bytes()).unwrap();
+        b.insert_key("baz", "3".as_bytes()).unwrap();
+        b.commit("").unwrap();
+        b.remove_key("baz").unwrap();
+        b.insert_key("foo", "1".as_bytes()).unwrap();
+        b.commit("").unwrap();
+        assert_eq!(a.content_fingerprint().unwrap(), b.content_fingerprint().unwrap());
+        assert_eq!(a.branch_fingerprint("master").unwrap(), b.fingerprint_at(b.head_oid().unwrap()).unwrap());
+        assert_eq!(a.fingerprint_at(first).unwrap() != a.content_fingerprint().unwrap(), true);
+        assert_eq!(a.content_fingerprint().unwrap() != empty, true);
+        b.insert_key("foo", "2".as_bytes()).unwrap();
+        assert_eq!(a.content_fingerprint().unwrap() != b.content_fingerprint().unwrap(), true);
+        assert_eq!(a.branch_fingerprint("missing").is_err(), true);
+        b.insert_key("foo", "1".as_bytes()).unwrap();
+        b.alias_key("qux", "foo").unwrap();
+        b.insert_rotating("log", "1".as_bytes(), 2).unwrap();
+        b.remove_key("log0").unwrap();
+        b.commit("").unwrap();
+        assert_eq!(a.content_fingerprint().unwrap(), b.content_fingerprint().unwrap());
+        assert_eq!(a.branch_fingerprint("master").unwrap(), b.branch_fingerprint("master").unwrap());
+    }
+}
@@ -0,0 +1,46 @@
+use chardetng::{EncodingDetector, Iso2022JpDetection, Utf8Detection};
+use crate::{Repo};
+
+/// Number of leading value bytes sampled when guessing the encoding.
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Encoding detection functions.
+impl Repo {
+
+    /// Guesses the character encoding of the key value, like `UTF-8` or
+    /// `windows-1252`, from its leading bytes. Returns `None` when the key is
+    /// missing or its value looks binary.
+    pub fn key_encoding(&self, name: &str) -> Option<String> {
+        let size = self.key_size(name)?;
+        let sample = self.key_range(name, 0, SAMPLE_SIZE)?;
+        if sample.contains(&0) {
+            return None;
+        }
+        let mut detector = EncodingDetector::new(Iso2022JpDetection::Allow);
+        detector.feed(&sample, sample.len() == size);
+        Some(detector.guess(None, Utf8Detection::Allow).name().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn guesses_key_encoding() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("utf8", "Grüße aus Köln".as_bytes()).unwrap();
+        repo.insert_key("latin", b"Gr\xfc\xdfe aus K\xf6ln, sch\xf6ne Stra\xdfe").unwrap();
+        repo.insert_key("binary", &[0x89, b'P', b'N', b'G', 0, 0, 0, 13]).unwrap();
+        assert_eq!(repo.key_encoding("utf8").unwrap(), "UTF-8");
+        assert_eq!(repo.key_encoding("latin").unwrap(), "windows-1252");
+        assert_eq!(repo.key_encoding("binary"), None);
+        assert_eq!(repo.key_encoding("missing"), None);
+        let mut large = b"Gr\xfc\xdfe aus K\xf6ln, sch\xf6ne Stra\xdfe ".repeat(SAMPLE_SIZE / 16);
+        large.push(0);
+        repo.insert_key("large", &large).unwrap();
+        assert_eq!(repo.key_encoding("large").unwrap(), "windows-1252");
+    }
+}
@@ -0,0 +1,148 @@
+use std::fmt;
+use std::str::Utf8Error;
+use std::time::Duration;
+use std::string::FromUtf8Error;
+use git2::{ErrorCode, ErrorClass, ObjectType};
+
+/// Error returned by repository operations.
+#[derive(Debug)]
+pub enum GitmapError {
+    /// Key, branch, commit or other object does not exist.
+    NotFound(String),
+    /// Data expected to be text is not valid UTF-8.
+    InvalidUtf8(String),
+    /// Change targets a tree entry written by another tool which is not a
+    /// value, like a submodule commit.
+    ForeignEntry {
+        /// Path of the entry.
+        key: String,
+        /// Type of the object the entry points at.
+        kind: ObjectType,
+    },
+    /// Operation did not finish within the contained timeout.
+    TimedOut(Duration),
+    /// Working branch has no commits yet.
+    NoCommits,
+    /// Underlying git operation failed.
+    Git(git2::Error),
+}
+
+impl GitmapError {
+
+    /// Returns a git error with the provided code and class. A `NotFound`
+    /// code results in the `NotFound` variant.
+    pub(crate) fn new<S: AsRef<str>>(code: ErrorCode, class: ErrorClass, message: S) -> Self {
+        Self::from(git2::Error::new(code, class, message))
+    }
+
+    /// Returns the git error code best describing the error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::NotFound(_) => ErrorCode::NotFound,
+            Self::InvalidUtf8(_) | Self::ForeignEntry { .. } => ErrorCode::Invalid,
+            Self::TimedOut(_) => ErrorCode::GenericError,
+            Self::NoCommits => ErrorCode::UnbornBranch,
+            Self::Git(err) => err.code(),
+        }
+    }
+
+    /// Returns the error message.
+    pub fn message(&self) -> &str {
+        match self {
+            Self::NotFound(message) | Self::InvalidUtf8(message) => message,
+            Self::ForeignEntry { .. } => "entry is not a value",
+            Self::TimedOut(_) => "operation timed out",
+            Self::NoCommits => "repository has no commits",
+            Self::Git(err) => err.message(),
+        }
+    }
+}
+
+impl fmt::Display for GitmapError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(message) => write!(f, "not found: {}", message),
+            Self::InvalidUtf8(message) => write!(f, "invalid UTF-8: {}", message),
+            Self::ForeignEntry { key, kind } => write!(f, "entry `{}` is a foreign {} and not a value", key, kind),
+            Self::TimedOut(timeout) => write!(f, "operation timed out after {:?}", timeout),
+            Self::NoCommits => write!(f, "repository has no commits"),
+            Self::Git(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for GitmapError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Git(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<git2::Error> for GitmapError {
+
+    fn from(err: git2::Error) -> Self {
+        match err.code() {
+            ErrorCode::NotFound => Self::NotFound(err.message().to_string()),
+            _ => Self::Git(err),
+        }
+    }
+}
+
+impl From<Utf8Error> for GitmapError {
+
+    fn from(err: Utf8Error) -> Self {
+        Self::InvalidUtf8(err.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for GitmapError {
+
+    fn from(err: FromUtf8Error) -> Self {
+        Self::InvalidUtf8(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_git_errors() {
+        let err = GitmapError::from(git2::Error::new(ErrorCode::NotFound, ErrorClass::Object, "missing"));
+        assert_eq!(matches!(&err, GitmapError::NotFound(message) if message == "missing"), true);
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert_eq!(err.to_string(), "not found: missing");
+        let err = GitmapError::new(ErrorCode::Locked, ErrorClass::Reference, "locked");
+        assert_eq!(matches!(err, GitmapError::Git(_)), true);
+        assert_eq!(err.code(), ErrorCode::Locked);
+        assert_eq!(err.message(), "locked");
+        assert_eq!(std::error::Error::source(&err).is_some(), true);
+    }
+
+    #[test]
+    fn describes_timeouts() {
+        let err = GitmapError::TimedOut(Duration::from_secs(2));
+        assert_eq!(err.code(), ErrorCode::GenericError);
+        assert_eq!(err.message(), "operation timed out");
+        assert_eq!(err.to_string(), "operation timed out after 2s");
+    }
+
+    #[test]
+    fn describes_missing_commits() {
+        let err = GitmapError::NoCommits;
+        assert_eq!(err.code(), ErrorCode::UnbornBranch);
+        assert_eq!(err.message(), "repository has no commits");
+        assert_eq!(err.to_string(), "repository has no commits");
+    }
+
+    #[test]
+    fn converts_utf8_errors() {
+        let err = GitmapError::from(String::from_utf8(vec![0xff]).unwrap_err());
+        assert_eq!(matches!(err, GitmapError::InvalidUtf8(_)), true);
+        assert_eq!(err.code(), ErrorCode::Invalid);
+    }
+}
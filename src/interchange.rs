@@ -0,0 +1,159 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::ops::ControlFlow;
+use git2::{ErrorCode, ErrorClass};
+use crate::{Repo, GitmapError, ImportPolicy, ImportReport, check_name};
+
+/// Encoding of a text value in the CSV `encoding` column.
+const TEXT_ENCODING: &str = "text";
+
+/// Encoding of a binary value in the CSV `encoding` column.
+const BASE64_ENCODING: &str = "base64";
+
+/// CSV interchange functions.
+impl Repo {
+
+    /// Writes all keys as `key,value,encoding` CSV rows with a header. Values
+    /// which are not valid UTF-8 are base64 encoded and marked as `base64`.
+    pub fn export_csv<W: Write>(&self, w: W) -> Result<(), GitmapError> {
+        let mut writer = csv::Writer::from_writer(w);
+        writer.write_record(["key", "value", "encoding"]).map_err(csv_error)?;
+        let mut failure = None;
+        self.for_each_entry(|key, entry| {
+            let written = self.repo.find_blob(entry.id).map_err(GitmapError::from).and_then(|blob| match std::str::from_utf8(blob.content()) {
+                Ok(text) => writer.write_record([key, text, TEXT_ENCODING]),
+                Err(_) => writer.write_record([key, &base64::encode(blob.content()), BASE64_ENCODING]),
+            }.map_err(csv_error));
+            match written {
+                Ok(_) => ControlFlow::Continue(()),
+                Err(err) => {
+                    failure = Some(err);
+                    ControlFlow::Break(())
+                },
+            }
+        })?;
+        if let Some(err) = failure {
+            return Err(err);
+        }
+        writer.flush().map_err(|err| GitmapError::new(ErrorCode::GenericError, ErrorClass::Os, err.to_string()))
+    }
+
+    /// Stages keys from CSV rows written by `export_csv`. All rows are parsed
+    /// before anything is staged. CSV carries no timestamps so `KeepNewest`
+    /// keeps existing keys.
+    pub fn import_csv<R: Read>(&mut self, r: R, policy: ImportPolicy) -> Result<ImportReport, GitmapError> {
+        let mut rows = Vec::new();
+        let mut reader = csv::Reader::from_reader(r);
+        for record in reader.records() {
+            let record = record.map_err(csv_error)?;
+            let (key, value) = match (record.get(0), record.get(1)) {
+                (Some(key), Some(value)) => (key.to_string(), value),
+                _ => return Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, "invalid CSV record")),
+            };
+            let value = match record.get(2).unwrap_or(TEXT_ENCODING) {
+                TEXT_ENCODING => value.as_bytes().to_vec(),
+                BASE64_ENCODING => base64::decode(value).map_err(|err| GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, err.to_string()))?,
+                encoding => return Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, format!("unknown value encoding `{}`", encoding))),
+            };
+            rows.push((key, value));
+        }
+        self.import_rows(rows, policy)
+    }
+
+    /// Stages imported rows according to the policy with a single tree write.
+    /// Rows with invalid names or values are counted as failed.
+    fn import_rows(&mut self, rows: Vec<(String, Vec<u8>)>, policy: ImportPolicy) -> Result<ImportReport, GitmapError> {
+        if policy == ImportPolicy::FailOnConflict {
+            let conflicts: Vec<&str> = rows.iter()
+                .filter(|(key, _)| self.staged_has_key(key))
+                .map(|(key, _)| key.as_str())
+                .collect();
+            if !conflicts.is_empty() {
+                return Err(GitmapError::new(ErrorCode::Exists, ErrorClass::Invalid, format!("import conflicts with existing keys: {}", conflicts.join(", "))));
+            }
+        }
+        let mut report = ImportReport::default();
+        let mut accepted = Vec::new();
+        let mut names = HashSet::new();
+        for (key, value) in rows {
+            let name = self.key_name(&key).into_owned();
+            let exists = names.contains(&name) || self.staged_has_key(&key);
+            if exists && policy != ImportPolicy::OverwriteAll {
+                report.skipped += 1;
+            } else if check_name(&name).and_then(|_| self.check_value(&name, &value)).is_err() {
+                report.failed += 1;
+            } else {
+                if exists {
+                    report.overwritten += 1;
+                } else {
+                    report.imported += 1;
+                }
+                names.insert(name);
+                accepted.push((key, value));
+            }
+        }
+        self.insert_keys(accepted)?;
+        Ok(report)
+    }
+}
+
+/// Converts a CSV error into an invalid data error.
+fn csv_error(err: csv::Error) -> GitmapError {
+    GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn exports_and_imports_csv() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("a,b", "1,2".as_bytes()).unwrap();
+        repo.insert_key("quote", "say \"hi\"\nbye".as_bytes()).unwrap();
+        repo.insert_key("binary", &[0, 159, 146, 150]).unwrap();
+        repo.insert_key("empty", "".as_bytes()).unwrap();
+        let mut data = Vec::new();
+        repo.export_csv(&mut data).unwrap();
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut copy = Repo::init(&path).unwrap();
+        copy.import_csv(data.as_slice(), ImportPolicy::OverwriteAll).unwrap();
+        assert_eq!(copy.keys(), repo.keys());
+        for key in repo.keys() {
+            assert_eq!(copy.key(&key), repo.key(&key));
+        }
+        let err = copy.import_csv("key,value,encoding\nfoo,bar,rot13\n".as_bytes(), ImportPolicy::OverwriteAll).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Invalid);
+    }
+
+    #[test]
+    fn imports_csv_with_policy() {
+        let data = "key,value,encoding\nfoo,new,text\nbar,new,text\n.gitmap.x,new,text\n";
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "old".as_bytes()).unwrap();
+        let report = repo.import_csv(data.as_bytes(), ImportPolicy::OverwriteAll).unwrap();
+        assert_eq!(report, ImportReport { imported: 1, skipped: 0, overwritten: 1, failed: 1 });
+        assert_eq!(repo.keys(), ["bar", "foo"]);
+        assert_eq!(repo.key("foo").unwrap(), "new".as_bytes());
+        for policy in [ImportPolicy::SkipExisting, ImportPolicy::KeepNewest] {
+            repo.reset().unwrap();
+            repo.insert_key("foo", "old".as_bytes()).unwrap();
+            let report = repo.import_csv(data.as_bytes(), policy).unwrap();
+            assert_eq!(report, ImportReport { imported: 1, skipped: 1, overwritten: 0, failed: 1 });
+            assert_eq!(repo.keys(), ["bar", "foo"]);
+            assert_eq!(repo.key("foo").unwrap(), "old".as_bytes());
+        }
+        repo.reset().unwrap();
+        repo.insert_key("foo", "old".as_bytes()).unwrap();
+        assert_eq!(repo.import_csv(data.as_bytes(), ImportPolicy::FailOnConflict).is_err(), true);
+        assert_eq!(repo.keys(), ["foo"]);
+        assert_eq!(repo.key("foo").unwrap(), "old".as_bytes());
+        repo.reset().unwrap();
+        let report = repo.import_csv("key,value\nqux,1\nqux,2\n".as_bytes(), ImportPolicy::OverwriteAll).unwrap();
+        assert_eq!(report, ImportReport { imported: 1, skipped: 0, overwritten: 1, failed: 0 });
+        assert_eq!(repo.key("qux").unwrap(), "2".as_bytes());
+    }
+}
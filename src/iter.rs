@@ -0,0 +1,193 @@
+use git2::{ObjectType, Oid, Repository, Tree};
+use crate::{Repo, GitmapError, EntryKind, is_meta};
+
+/// Key yielded by `Iter`. The value is only read when asked for.
+pub struct Entry<'r> {
+    repo: &'r Repository,
+    name: String,
+    id: Oid,
+    kind: EntryKind,
+}
+
+/// Lazy iterator over keys in git tree order. Trees are loaded one level at a
+/// time while walking, so keys are never collected up front.
+pub struct Iter<'r> {
+    repo: &'r Repository,
+    prefix: String,
+    include_foreign: bool,
+    stack: Vec<(Tree<'r>, usize, String)>,
+    failure: Option<GitmapError>,
+}
+
+/// Iterator functions.
+impl Repo {
+
+    /// Iterates over all keys including staged changes. The repository is
+    /// only borrowed immutably so values can be read while iterating.
+    pub fn iter(&self) -> Iter<'_> {
+        self.iter_prefix("")
+    }
+
+    /// Iterates over names of all keys including staged changes.
+    pub fn iter_names(&self) -> impl Iterator<Item = Result<String, GitmapError>> + '_ {
+        self.iter().map(|entry| entry.map(|entry| entry.name))
+    }
+
+    /// Iterates over keys starting with the provided prefix. Subtrees which
+    /// can not hold matching keys are skipped without being read.
+    pub fn iter_prefix(&self, prefix: &str) -> Iter<'_> {
+        self.iter_tree(self.read_tree_id(), prefix)
+    }
+
+    /// Iterates over keys of the tree starting with the provided prefix.
+    pub(crate) fn iter_tree(&self, tree_id: Result<Oid, GitmapError>, prefix: &str) -> Iter<'_> {
+        let mut iter = Iter { repo: &self.repo, prefix: self.key_name(prefix).into_owned(), include_foreign: self.include_foreign, stack: Vec::new(), failure: None };
+        match tree_id.and_then(|id| Ok(self.repo.find_tree(id)?)) {
+            Ok(tree) => iter.stack.push((tree, 0, String::new())),
+            Err(GitmapError::NoCommits) => {},
+            Err(err) => iter.failure = Some(err),
+        }
+        iter
+    }
+}
+
+impl<'r> Entry<'r> {
+
+    /// Returns the key name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the blob ID of the value.
+    pub fn id(&self) -> Oid {
+        self.id
+    }
+
+    /// Returns the entry kind. Foreign entries are only yielded when enabled
+    /// with `set_include_foreign` and have no value.
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    /// Returns the value size in bytes without reading the value.
+    pub fn size(&self) -> Result<usize, GitmapError> {
+        Ok(self.repo.odb()?.read_header(self.id)?.0)
+    }
+
+    /// Reads the value.
+    pub fn value(&self) -> Result<Vec<u8>, GitmapError> {
+        Ok(self.repo.find_blob(self.id)?.content().to_vec())
+    }
+}
+
+impl<'r> Iterator for Iter<'r> {
+    type Item = Result<Entry<'r>, GitmapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.failure.take() {
+            self.stack.clear();
+            return Some(Err(err));
+        }
+        loop {
+            let (tree, index, dir) = self.stack.last_mut()?;
+            let entry = tree.get(*index).map(|entry| (entry.name().map(|name| format!("{}{}", dir, name)), entry.kind(), entry.id()));
+            let (name, kind, id) = match entry {
+                Some((Some(name), kind, id)) if !(dir.is_empty() && is_meta(&name)) => (name, kind, id),
+                Some(_) => {
+                    *index += 1;
+                    continue;
+                },
+                None => {
+                    self.stack.pop();
+                    continue;
+                },
+            };
+            *index += 1;
+            match kind {
+                Some(ObjectType::Blob) if name.starts_with(&self.prefix) => {
+                    return Some(Ok(Entry { repo: self.repo, name, id, kind: EntryKind::Blob }));
+                },
+                Some(ObjectType::Tree) => {
+                    let dir = format!("{}/", name);
+                    if !dir.starts_with(&self.prefix) && !self.prefix.starts_with(&dir) {
+                        continue;
+                    }
+                    match self.repo.find_tree(id) {
+                        Ok(tree) => self.stack.push((tree, 0, dir)),
+                        Err(err) => {
+                            self.stack.clear();
+                            return Some(Err(err.into()));
+                        },
+                    }
+                },
+                _ if self.include_foreign && name.starts_with(&self.prefix) => {
+                    return Some(Ok(Entry { repo: self.repo, name, id, kind: EntryKind::Other }));
+                },
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+
+    /// Collects names yielded by the iterator.
+    fn names(iter: Iter) -> Vec<String> {
+        iter.map(|entry| entry.unwrap().name().to_string()).collect()
+    }
+
+    #[test]
+    fn iterates_in_tree_order() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("b", "2".as_bytes()).unwrap();
+        repo.insert_key("a.txt", "1".as_bytes()).unwrap();
+        repo.insert_key("a/c", "3".as_bytes()).unwrap();
+        repo.alias_key("z", "b").unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(names(repo.iter()), ["a.txt", "a/c", "b"]);
+        assert_eq!(repo.iter_names().collect::<Result<Vec<_>, _>>().unwrap(), ["a.txt", "a/c", "b"]);
+        let entry = repo.iter().nth(1).unwrap().unwrap();
+        assert_eq!(entry.size().unwrap(), 1);
+        assert_eq!(entry.value().unwrap(), "3".as_bytes());
+    }
+
+    #[test]
+    fn iterates_empty_repository() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.iter().count(), 0);
+        assert_eq!(repo.iter_names().count(), 0);
+    }
+
+    #[test]
+    fn iterates_staged_keys() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("bar", "2".as_bytes()).unwrap();
+        repo.remove_key("foo").unwrap();
+        for entry in repo.iter() {
+            let entry = entry.unwrap();
+            assert_eq!(repo.key(entry.name()), Some(entry.value().unwrap()));
+        }
+        assert_eq!(names(repo.iter()), ["bar"]);
+    }
+
+    #[test]
+    fn iterates_keys_with_prefix() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("user:1", "".as_bytes()).unwrap();
+        repo.insert_key("users/a", "".as_bytes()).unwrap();
+        repo.insert_key("users/b/c", "".as_bytes()).unwrap();
+        repo.insert_key("other/user:2", "".as_bytes()).unwrap();
+        assert_eq!(names(repo.iter_prefix("user")), ["user:1", "users/a", "users/b/c"]);
+        assert_eq!(names(repo.iter_prefix("users/b")), ["users/b/c"]);
+        assert_eq!(names(repo.iter_prefix("missing")).len(), 0);
+    }
+}
@@ -1,7 +1,80 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::ops::ControlFlow;
 use std::path::{Path};
-use git2::{Repository, BranchType, Oid, DiffOptions};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use git2::{Blob, Commit, Delta, Diff, DiffDelta, DiffFlags, DiffOptions, FileMode, Patch, Sort, ErrorCode, ErrorClass, Tree, TreeBuilder, TreeWalkMode, TreeWalkResult};
 
-pub use git2::Error;
+pub use git2::{BranchType, Cred, ObjectType, Oid, RemoteCallbacks, Repository, Signature, Time};
+
+mod annotations;
+mod error;
+mod batch;
+mod iter;
+mod merge;
+mod remote;
+mod slow;
+#[cfg(feature = "crdt")]
+mod crdt;
+#[cfg(feature = "csv")]
+mod interchange;
+#[cfg(feature = "digest")]
+mod digest;
+#[cfg(feature = "encoding")]
+mod encoding;
+#[cfg(feature = "runner")]
+mod mirror;
+#[cfg(feature = "runner")]
+mod queue;
+#[cfg(feature = "serde")]
+mod typed;
+
+pub use annotations::Annotations;
+pub use batch::{Batch, BulkError, BulkLoader, BulkOptions, BulkSummary};
+pub use error::GitmapError;
+pub use iter::{Entry, Iter};
+pub use merge::{MergeOutcome, MergeStrategy};
+pub use slow::SlowOp;
+#[cfg(feature = "digest")]
+pub use digest::TamperFinding;
+#[cfg(feature = "runner")]
+pub use mirror::{BranchUpdate, MirrorEvent, MirrorRunner, MirrorStatus};
+#[cfg(feature = "runner")]
+pub use queue::{CommitQueue, KeyOp, QueueHandle};
+#[cfg(feature = "serde")]
+pub use typed::KeyError;
+
+/// Commit identity used when none is configured.
+const DEFAULT_IDENTITY: (&str, &str) = ("gitmap", "gitmap@localhost");
+
+/// Number of retries of ref updates failing on a lock.
+const DEFAULT_LOCK_RETRIES: usize = 3;
+
+/// Delay before the first retry of a locked ref update.
+const DEFAULT_LOCK_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Prefix of reserved tree entries holding gitmap metadata.
+const META_PREFIX: &str = ".gitmap.";
+
+/// Metadata entry holding key aliases.
+const ALIASES_META: &str = ".gitmap.aliases";
+
+/// Metadata entry holding the state of rotating keys.
+const ROTATING_META: &str = ".gitmap.rotating";
+
+/// Number of bytes read at once when streaming part of a value.
+const RANGE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Maximum number of aliases followed when resolving a name.
+const MAX_ALIAS_DEPTH: usize = 8;
+
+/// Maximum number of names remembered by the negative cache.
+const NEGATIVE_CACHE_CAPACITY: usize = 10_000;
 
 /// Structure properties.
 pub struct Repo {
@@ -9,26 +82,362 @@ pub struct Repo {
     repo: Repository,
     /// Temporial tree id.
     tree_id: Option<Oid>,
+    /// Commit the staged tree was derived from, `None` without commits.
+    staged_base: Cell<Option<Oid>>,
+    /// Keys written since the last commit, including no-op writes.
+    staged_keys: RefCell<BTreeSet<String>>,
+    /// Forced commit identity `(name, email, epoch)` in deterministic mode.
+    deterministic: Option<(String, String, i64)>,
+    /// Commit identity `(name, email)` used instead of the git config.
+    identity: Option<(String, String)>,
+    /// Whether key names are normalized to lowercase.
+    case_insensitive: bool,
+    /// Whether reads ignore staged changes.
+    strict_committed_reads: bool,
+    /// Whether listings include tree entries which are not values.
+    include_foreign: bool,
+    /// Maximum size of a value in bytes.
+    max_value_size: Option<usize>,
+    /// Number of retries and the initial backoff of locked ref updates.
+    lock_retries: (usize, Duration),
+    /// Missing names of the tree with the contained ID when enabled, mapped
+    /// to whether they also do not resolve as aliases.
+    negative_cache: Option<RefCell<(Oid, HashMap<String, bool>)>>,
+    /// Threshold and sink of slow operation reports when enabled.
+    slow_ops: Option<slow::SlowOpSink>,
+    /// ID replicated counter and set changes are recorded under.
+    #[cfg(feature = "crdt")]
+    writer_id: Option<String>,
+    /// Whether commits carry a tree digest trailer.
+    #[cfg(feature = "digest")]
+    tamper_evidence: bool,
+}
+
+/// Describes what a name resolves to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolved {
+    /// Name is a real key.
+    Key,
+    /// Name is an alias pointing to the contained name.
+    Alias(String),
+    /// Name does not exist.
+    Missing,
+}
+
+/// Statistics of staged values compared to the last commit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StageReport {
+    /// Number of staged values not stored by the last commit.
+    pub new_blobs: usize,
+    /// Total size of new values in bytes.
+    pub new_bytes: u64,
+    /// Number of changed keys whose value is already stored by the last
+    /// commit or another staged key.
+    pub dedup_hits: usize,
+    /// Number of written keys whose value equals the committed one.
+    pub unchanged: usize,
+}
+
+/// Distribution of value sizes returned by `value_size_histogram`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// Inclusive upper bound of each bucket with the number of keys in it.
+    /// Values above the largest bound land in a final `u64::MAX` bucket.
+    pub buckets: Vec<(u64, usize)>,
+    /// Number of empty values.
+    pub zero_sized: usize,
+    /// Size of the largest value in bytes.
+    pub max_size: u64,
+}
+
+/// Store metrics returned by `metrics`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreMetrics {
+    /// Number of keys.
+    pub keys: usize,
+    /// Total size of values in bytes.
+    pub value_bytes: u64,
+    /// Number of commits on the working branch.
+    pub commits: usize,
+    /// Number of local branches.
+    pub branches: usize,
+    /// Time of the last commit in seconds since the epoch.
+    pub last_commit_time: Option<i64>,
+}
+
+/// Describes what a tree entry listed as a key points at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Value blob.
+    Blob,
+    /// Foreign entry written by another tool, like a submodule commit.
+    Other,
+}
+
+/// Tree entry of a key passed to `for_each_entry`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TreeEntryLite {
+    /// Blob ID of the value or object ID of a foreign entry.
+    pub(crate) id: Oid,
+    /// Git file mode of the entry.
+    pub(crate) filemode: i32,
+    /// Kind of the entry.
+    pub(crate) kind: EntryKind,
+}
+
+/// Describes a staged key change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyChange {
+    /// Key is new.
+    Added(String),
+    /// Key value has changed.
+    Modified(String),
+    /// Key has been removed.
+    Removed(String),
+}
+
+/// Values of a key as `(commit, time, value)`, `None` marking a removal.
+pub type KeyHistory = Vec<(Oid, i64, Option<Vec<u8>>)>;
+
+/// Size and history of a key returned by `key_meta` and `keys_meta`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyMeta {
+    /// Value size in bytes.
+    pub size: usize,
+    /// Commit which last changed the key, `None` when the value is staged.
+    pub modified: Option<Oid>,
+    /// Time of the commit which last changed the key in seconds since the
+    /// epoch, `None` when the value is staged.
+    pub modified_time: Option<i64>,
+    /// Oldest commit which added the key, `None` when it was never committed.
+    pub created: Option<Oid>,
+}
+
+/// Keys which differ between two revisions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyDiff {
+    /// Keys only present in the second revision.
+    pub added: Vec<String>,
+    /// Keys only present in the first revision.
+    pub removed: Vec<String>,
+    /// Keys with different values.
+    pub modified: Vec<String>,
+}
+
+/// Commit properties.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitInfo {
+    /// Commit ID.
+    pub oid: Oid,
+    /// Author name.
+    pub author_name: String,
+    /// Author email.
+    pub author_email: String,
+    /// Commit time in seconds since the epoch.
+    pub timestamp: i64,
+    /// Commit message.
+    pub message: String,
+}
+
+/// Receipt of a commit returned by `commit_detailed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitResult {
+    /// New commit ID.
+    pub oid: Oid,
+    /// Number of added keys.
+    pub added: usize,
+    /// Number of keys with changed values.
+    pub modified: usize,
+    /// Number of removed keys.
+    pub removed: usize,
+    /// Whether the commit is the first one on the branch.
+    pub is_initial: bool,
+}
+
+/// Commit properties functions.
+impl CommitInfo {
+
+    /// Returns the commit ID as a hex string.
+    pub fn id(&self) -> String {
+        self.oid.to_string()
+    }
+}
+
+/// Describes how imports treat keys which already exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportPolicy {
+    /// Existing keys are overwritten.
+    OverwriteAll,
+    /// Existing keys are kept.
+    SkipExisting,
+    /// The import fails without staging anything when any key exists.
+    FailOnConflict,
+    /// The newer value wins when the source carries timestamps, otherwise
+    /// existing keys are kept.
+    KeepNewest,
+}
+
+/// Import statistics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Number of new keys.
+    pub imported: usize,
+    /// Number of existing keys left untouched.
+    pub skipped: usize,
+    /// Number of existing keys overwritten.
+    pub overwritten: usize,
+    /// Number of keys which could not be staged.
+    pub failed: usize,
+}
+
+/// Reader of a blob returned by `key_reader`.
+struct BlobReader<'r> {
+    blob: Blob<'r>,
+    position: usize,
+}
+
+impl<'r> Read for BlobReader<'r> {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let rest = &self.blob.content()[self.position..];
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// State of a rotating key prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Ring {
+    /// Prefix of slot names.
+    prefix: String,
+    /// Number of slots.
+    capacity: usize,
+    /// Index of the slot written next.
+    next: usize,
+    /// Number of written slots.
+    count: usize,
 }
 
 /// Repo functions.
 impl Repo {
 
     /// Creates a new `--bare` repository in the specified folder.
-    pub fn init<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        Ok(Self::new(Repository::init_bare(path)?))
+    ///
+    /// Values are arbitrary bytes and keys are case-sensitive, so git's
+    /// line-ending conversion, case folding and unicode precomposition are
+    /// explicitly disabled to behave the same on every platform. Ref updates
+    /// are logged, which bare repositories skip by default, so history moves
+    /// can be undone with `restore_from_reflog`.
+    pub fn init<P: AsRef<Path>>(path: P) -> Result<Self, GitmapError> {
+        let repo = Repository::init_bare(path)?;
+        configure(&repo)?;
+        Ok(Self::new(repo))
     }
 
     /// Opens an existing repository.
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, GitmapError> {
         Ok(Self::new(Repository::open_bare(path)?))
     }
 
+    /// Creates a new repository with a working directory in the specified
+    /// folder, configured like `init`. Commits only move refs, so the working
+    /// directory and the index are left untouched.
+    pub fn init_worktree<P: AsRef<Path>>(path: P) -> Result<Self, GitmapError> {
+        let repo = Repository::init(path)?;
+        configure(&repo)?;
+        Ok(Self::new(repo))
+    }
+
+    /// Opens an existing repository with a working directory, for example a
+    /// normal checkout.
+    pub fn open_worktree<P: AsRef<Path>>(path: P) -> Result<Self, GitmapError> {
+        Ok(Self::new(Repository::open(path)?))
+    }
+
+    /// Opens the repository enclosing the provided path by walking up parent
+    /// directories until a repository is found.
+    pub fn discover<P: AsRef<Path>>(start: P) -> Result<Self, GitmapError> {
+        Ok(Self::new(Repository::discover(start)?))
+    }
+
+    /// Opens an existing repository but gives up when the `timeout` passes
+    /// first with `GitmapError::TimedOut`. The open runs on a helper thread
+    /// which is leaked on timeout because a call blocked on a hung filesystem
+    /// can not be interrupted.
+    pub fn open_with_timeout<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<Self, GitmapError> {
+        let path = path.as_ref().to_owned();
+        with_timeout(timeout, move || Self::open(path))
+    }
+
+    /// Reads a single key from the tip of the `branch` (or HEAD) without
+    /// keeping the repository open. Returns `None` when the key or any commit
+    /// is missing and errors when the named branch does not exist.
+    pub fn read_key<P: AsRef<Path>>(path: P, branch: Option<&str>, name: &str) -> Result<Option<Vec<u8>>, GitmapError> {
+        let repo = Self::open(path)?;
+        let tree_id = match branch {
+            Some(branch) => repo.branch_tree_id(branch)?,
+            None if repo.has_commits() => repo.last_tree_id()?,
+            None => return Ok(None),
+        };
+        Ok(repo.tree_value(tree_id, name))
+    }
+
+    /// Writes a single key and commits it to the `branch` (or HEAD) without
+    /// keeping the repository open. Returns the new commit ID and errors when
+    /// the named branch does not exist. With the `digest` feature the commit
+    /// gets a digest trailer when the `gitmap.tamperEvidence` config is set.
+    pub fn write_key<P: AsRef<Path>>(path: P, branch: Option<&str>, name: &str, value: &[u8], message: &str) -> Result<Oid, GitmapError> {
+        #[allow(unused_mut)]
+        let mut repo = Self::open(path)?;
+        #[cfg(feature = "digest")]
+        {
+            repo.tamper_evidence = repo.repo.config()?.get_bool(digest::TAMPER_EVIDENCE_CONFIG).unwrap_or(false);
+        }
+        let name = &*repo.key_name(name);
+        check_name(name)?;
+        repo.check_value(name, value)?;
+        let (refname, parent) = match branch {
+            Some(branch) => {
+                let branch = repo.repo.find_branch(branch, BranchType::Local)?;
+                let refname = branch.get().name().unwrap_or("HEAD").to_string();
+                (Some(refname), Some(branch.get().peel_to_commit()?.id()))
+            },
+            None => (None, repo.branch_tip_id()?),
+        };
+        let base = match parent {
+            Some(id) => Some(repo.repo.find_commit(id)?.tree_id()),
+            None => None,
+        };
+        let tree_id = repo.update_tree(base, &[(name, Some(repo.write_blob(value)?))])?;
+        let sig = repo.signature()?;
+        match refname {
+            Some(refname) => repo.commit_tree_to(&refname, parent.as_slice(), tree_id, message, &sig),
+            None => repo.commit_tree(tree_id, message, &sig),
+        }
+    }
+
     /// Returns a new repo object.
     fn new(repo: Repository) -> Self {
         Self {
             repo,
             tree_id: None,
+            staged_base: Cell::new(None),
+            staged_keys: RefCell::new(BTreeSet::new()),
+            deterministic: None,
+            identity: None,
+            case_insensitive: false,
+            strict_committed_reads: false,
+            include_foreign: false,
+            max_value_size: None,
+            lock_retries: (DEFAULT_LOCK_RETRIES, DEFAULT_LOCK_BACKOFF),
+            negative_cache: None,
+            slow_ops: None,
+            #[cfg(feature = "crdt")]
+            writer_id: None,
+            #[cfg(feature = "digest")]
+            tamper_evidence: false,
         }
     }
 
@@ -37,9 +446,92 @@ impl Repo {
         self.repo.path()
     }
 
-    /// Returns the number of all keys.
+    /// Gives access to the underlying repository for operations gitmap does
+    /// not cover. Staged changes are discarded when the closure moves HEAD or
+    /// removes the staged tree so later calls see the new repository state.
+    pub fn with_repository<R, F: FnOnce(&mut Repository) -> R>(&mut self, f: F) -> R {
+        let head = self.last_commit_id().ok();
+        let result = f(&mut self.repo);
+        let stale = match self.tree_id {
+            Some(id) => self.repo.find_tree(id).is_err(),
+            None => false,
+        };
+        if stale || self.last_commit_id().ok() != head {
+            self.set_staged_tree(None);
+        }
+        result
+    }
+
+    /// Gives read-only access to the underlying repository.
+    pub fn with_repository_ref<R, F: FnOnce(&Repository) -> R>(&self, f: F) -> R {
+        f(&self.repo)
+    }
+
+    /// Returns the underlying repository for operations gitmap does not cover.
+    /// Writes through it bypass staging, so moving HEAD this way leaves staged
+    /// changes based on the old commit; prefer `with_repository` for writes.
+    pub fn repository(&self) -> &Repository {
+        &self.repo
+    }
+
+    /// Returns the ID of the commit HEAD points to, also when detached. Errors
+    /// with `GitmapError::NoCommits` when the working branch has no commits.
+    pub fn head_oid(&self) -> Result<Oid, GitmapError> {
+        match self.repo.head() {
+            Ok(head) => Ok(head.peel_to_commit()?.id()),
+            Err(err) if err.code() == ErrorCode::UnbornBranch || err.code() == ErrorCode::NotFound => Err(GitmapError::NoCommits),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Returns the ID of the commit at the tip of the branch.
+    pub fn branch_oid(&self, name: &str) -> Result<Oid, GitmapError> {
+        Ok(self.repo.find_branch(name, BranchType::Local)?.get().peel_to_commit()?.id())
+    }
+
+    /// Returns the ID of the tree the next commit will store. Without staged
+    /// changes this is the committed tree or the empty tree without commits.
+    pub fn staged_tree_oid(&self) -> Result<Oid, GitmapError> {
+        if let Some(tree_id) = self.tree_id {
+            Ok(tree_id)
+        } else if !self.has_commits() {
+            self.empty_tree_id()
+        } else {
+            self.committed_tree_oid()
+        }
+    }
+
+    /// Returns the ID of the tree of the commit HEAD points to. Errors with
+    /// `GitmapError::NoCommits` when the working branch has no commits.
+    pub fn committed_tree_oid(&self) -> Result<Oid, GitmapError> {
+        Ok(self.repo.find_commit(self.head_oid()?)?.tree_id())
+    }
+
+    /// Returns the number of all keys including staged changes.
     pub fn len(&self) -> usize {
-        self.keys().len()
+        match self.read_tree_id() {
+            Ok(id) => self.tree_len(id),
+            Err(_) => 0,
+        }
+    }
+
+    /// Returns true if there are no keys including staged changes. Stops at
+    /// the first key found.
+    pub fn is_empty(&self) -> bool {
+        let mut empty = true;
+        let _ = self.for_each_entry(|_, _| {
+            empty = false;
+            ControlFlow::Break(())
+        });
+        empty
+    }
+
+    /// Returns the number of keys in the last commit.
+    pub fn len_committed(&self) -> usize {
+        match self.last_tree_id() {
+            Ok(id) => self.tree_len(id),
+            Err(_) => 0,
+        }
     }
 
     /// Returns true if repository has no commits.
@@ -52,12 +544,12 @@ impl Repo {
 
     /// Returns true if at least one branch exists.
     pub fn has_branches(&self) -> bool {
-        self.branches().len() > 0
+        !self.branches().is_empty()
     }
 
-    /// Returns true if at least one key exists.
+    /// Returns true if at least one key exists. Stops at the first key found.
     pub fn has_keys(&self) -> bool {
-        self.keys().len() > 0
+        !self.is_empty()
     }
 
     /// Returns true if the provided branch exists.
@@ -67,473 +559,3683 @@ impl Repo {
 
     /// Returns true if the key exists.
     pub fn has_key(&self, name: &str) -> bool {
-        let tree = match self.current_tree_id() {
-            Ok(id) => match self.repo.find_tree(id) {
-                Ok(tree) => tree,
-                Err(_) => return false,
-            },
-            Err(_) => return false,
-        };
-        let name = tree.get_name(name);
-        name.is_some()
+        self.tree_has_key(self.read_tree_id(), &self.key_name(name))
     }
     
     /// Returns working branch name.
     pub fn branches(&self) -> Vec<String> {
-        let mut names = Vec::new();
-
-        let branches = match self.repo.branches(Some(BranchType::Local)) {
-            Err(_) => return names,
-            Ok(iter) => iter,
-        };
+        self.try_branches().unwrap_or_default()
+    }
 
-        for item in branches {
-            names.push(
-                match item {
-                    Ok(i) => match i.0.name() {
-                        Ok(n) => match n {
-                            Some(n) => n.to_string(),
-                            None => break,
-                        },
-                        Err(_) => break,
-                    },
-                    Err(_) => break,
-                },
-            );
+    /// List local branch names. Errors when any branch can not be read.
+    pub fn try_branches(&self) -> Result<Vec<String>, GitmapError> {
+        let mut names = Vec::new();
+        for item in self.repo.branches(Some(BranchType::Local))? {
+            match item?.0.name()? {
+                Some(name) => names.push(name.to_string()),
+                None => return Err(GitmapError::InvalidUtf8("branch name is not valid UTF-8".to_string())),
+            }
         }
-        names
+        Ok(names)
     }
 
     /// List all available keys.
     pub fn keys(&self) -> Vec<String> {
-        let mut paths: Vec<String> = Vec::new();
+        self.try_keys().unwrap_or_default()
+    }
+
+    /// List all available keys. Errors when the tree can not be read.
+    pub fn try_keys(&self) -> Result<Vec<String>, GitmapError> {
+        let started = self.slow_op_start();
+        let keys = self.read_tree_id().and_then(|id| self.tree_keys(id));
+        self.report_slow_op(started, "keys", None, 0);
+        keys
+    }
+
+    /// List all keys with their values including staged changes. The tree is
+    /// walked once which is much faster than calling `key()` for each name.
+    /// Foreign entries carry no value and are always left out.
+    pub fn entries(&self) -> Result<Vec<(String, Vec<u8>)>, GitmapError> {
+        let mut entries = Vec::new();
+        let mut failure = None;
+        self.for_each_entry(|name, entry| match self.repo.find_blob(entry.id) {
+            _ if entry.kind == EntryKind::Other => ControlFlow::Continue(()),
+            Ok(blob) => {
+                entries.push((name.to_string(), blob.content().to_vec()));
+                ControlFlow::Continue(())
+            },
+            Err(err) => {
+                failure = Some(err);
+                ControlFlow::Break(())
+            },
+        })?;
+        match failure {
+            Some(err) => Err(err.into()),
+            None => Ok(entries),
+        }
+    }
+
+    /// List keys committed on the provided branch.
+    pub fn keys_on_branch(&self, branch: &str) -> Result<Vec<String>, GitmapError> {
+        self.tree_keys(self.branch_tree_id(branch)?)
+    }
+
+    /// List keys existing on both branches.
+    pub fn keys_intersection(&self, a: &str, b: &str) -> Result<Vec<String>, GitmapError> {
+        let b: BTreeSet<String> = self.keys_on_branch(b)?.into_iter().collect();
+        Ok(self.keys_on_branch(a)?.into_iter().filter(|k| b.contains(k)).collect())
+    }
+
+    /// List keys existing on any of the branches.
+    pub fn keys_union(&self, a: &str, b: &str) -> Result<Vec<String>, GitmapError> {
+        let mut keys: BTreeSet<String> = self.keys_on_branch(a)?.into_iter().collect();
+        keys.extend(self.keys_on_branch(b)?);
+        Ok(keys.into_iter().collect())
+    }
+
+    /// List keys existing on branch `a` but not on branch `b`.
+    pub fn keys_difference(&self, a: &str, b: &str) -> Result<Vec<String>, GitmapError> {
+        let b: BTreeSet<String> = self.keys_on_branch(b)?.into_iter().collect();
+        Ok(self.keys_on_branch(a)?.into_iter().filter(|k| !b.contains(k)).collect())
+    }
 
-        let tree = match self.current_tree_id() {
+    /// List keys below the provided directory-style prefix like `config/app`.
+    pub fn keys_under(&self, prefix: &str) -> Vec<String> {
+        let prefix = self.key_name(prefix);
+        let prefix = prefix.trim_end_matches('/');
+        if prefix.is_empty() {
+            return self.keys();
+        }
+        let tree = match self.read_tree_id() {
             Ok(id) => match self.repo.find_tree(id) {
                 Ok(tree) => tree,
-                Err(_) => return paths,
+                Err(_) => return Vec::new(),
             },
-            Err(_) => return paths,
+            Err(_) => return Vec::new(),
+        };
+        match tree.get_path(Path::new(prefix)) {
+            Ok(entry) if entry.kind() == Some(ObjectType::Tree) => self.tree_keys(entry.id()).unwrap_or_default().into_iter()
+                .map(|key| format!("{}/{}", prefix, key))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// List keys starting with the provided prefix including staged changes.
+    /// Subtrees outside the prefix are not read.
+    pub fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.iter_prefix(prefix).map_while(Result::ok).map(|entry| entry.name().to_string()).collect()
+    }
+
+    /// List keys matching the provided glob pattern including staged changes.
+    /// `?` matches any single character and `*` any sequence, including `/`.
+    pub fn keys_matching(&self, pattern: &str) -> Vec<String> {
+        let pattern = self.key_name(pattern);
+        let literal = pattern.split(['*', '?']).next().unwrap_or("");
+        self.iter_prefix(literal).map_while(Result::ok)
+            .filter(|entry| glob_match(&pattern, entry.name()))
+            .map(|entry| entry.name().to_string())
+            .collect()
+    }
+
+    /// Counts keys of the provided tree including nested ones.
+    fn tree_len(&self, tree_id: Oid) -> usize {
+        let tree = match self.repo.find_tree(tree_id) {
+            Ok(tree) => tree,
+            Err(_) => return 0,
         };
+        let mut count = 0;
+        let walked = tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            if entry.kind() == Some(ObjectType::Blob) && !(dir.is_empty() && is_meta(entry.name().unwrap_or(""))) {
+                count += 1;
+            }
+            TreeWalkResult::Ok
+        });
+        match walked {
+            Ok(_) => count,
+            Err(_) => 0,
+        }
+    }
+
+    /// List all keys of the provided tree.
+    fn tree_keys(&self, tree_id: Oid) -> Result<Vec<String>, GitmapError> {
+        let mut paths: Vec<String> = Vec::new();
+
+        let tree = self.repo.find_tree(tree_id)?;
         let mut opts = DiffOptions::new();
             opts.include_unmodified(true);
-        let diff = match self.repo.diff_tree_to_tree(Some(&tree), None, Some(&mut opts)) {
-            Ok(diff) => diff,
-            Err(_) => return paths,
-        };
+        let diff = self.repo.diff_tree_to_tree(Some(&tree), None, Some(&mut opts))?;
         
         for item in diff.deltas() {
+            if !self.include_foreign && item.old_file().mode() != FileMode::Blob && item.old_file().mode() != FileMode::BlobExecutable {
+                continue;
+            }
             paths.push(
                 match item.old_file().path() {
                     Some(path) => match path.to_str() {
-                        Some(path) => path.to_string(),
-                        None => continue,
+                        Some(path) if !is_meta(path) => path.to_string(),
+                        _ => continue,
                     },
                     None => continue,
                 },
             );
         }
-        paths
+        Ok(paths)
+    }
+
+    /// List all available keys including aliases.
+    pub fn keys_with_aliases(&self) -> Vec<String> {
+        let mut names = self.keys();
+        names.extend(self.aliases().into_iter().map(|(alias, _)| alias));
+        names.sort();
+        names
+    }
+
+    /// List all aliases as `(alias, target)` pairs.
+    pub fn aliases(&self) -> Vec<(String, String)> {
+        match self.meta(ALIASES_META) {
+            Some(data) => decode_aliases(&data),
+            None => Vec::new(),
+        }
+    }
+
+    /// List aliases which no longer resolve to an existing key.
+    pub fn dangling_aliases(&self) -> Vec<String> {
+        let tree_id = match self.current_tree_id() {
+            Ok(id) => id,
+            Err(_) => return Vec::new(),
+        };
+        self.aliases().into_iter()
+            .filter(|(alias, _)| !matches!(self.resolve_alias(tree_id, alias), Ok(Some(_))))
+            .map(|(alias, _)| alias)
+            .collect()
+    }
+
+    /// Resolves a git revision like `HEAD~2`, `master@{1}`, `v1^{commit}` or
+    /// an abbreviated ID to a commit ID. Tags are peeled to the commit they
+    /// point at. Errors when the revision does not exist or does not point at
+    /// a commit, like `HEAD:key`.
+    pub fn resolve_rev(&self, rev: &str) -> Result<Oid, GitmapError> {
+        Ok(self.repo.revparse_single(rev)?.peel_to_commit()?.id())
+    }
+
+    /// Tells whether the name is a real key, an alias or missing.
+    pub fn resolve(&self, name: &str) -> Resolved {
+        let name = &*self.key_name(name);
+        if self.has_key(name) {
+            return Resolved::Key;
+        }
+        match self.aliases().into_iter().find(|(alias, _)| alias == name) {
+            Some((_, target)) => Resolved::Alias(target),
+            None => Resolved::Missing,
+        }
     }
 
     /// Returns working branch name.
     pub fn branch(&self) -> Option<String> {
         match self.repo.head() {
             Ok(head) => match head.name() {
-                Some(name) => match name.split("refs/heads/").last() {
-                    Some(name) => Some(name.to_string()),
-                    None => None,
-                },
+                Some(name) => name.split("refs/heads/").last().map(|n| n.to_string()),
                 None => None,
             },
             Err(_) => None,
         }
     }
 
-    /// Retrieves key content.
+    /// Retrieves key content. Aliases are followed transparently.
     pub fn key(&self, name: &str) -> Option<Vec<u8>> {
-        let tree = match self.current_tree_id() {
-            Ok(id) => match self.repo.find_tree(id) {
-                Ok(tree) => tree,
-                Err(_) => return None,
-            },
-            Err(_) => return None,
-        };
-        let content = match tree.get_name(name) {
-            Some(entry) => match entry.to_object(&self.repo) {
-                Ok(blob) => match blob.as_blob() {
-                    Some(data) => data.content().to_vec(),
-                    None => return None,
-                },
-                Err(_) => return None,
-            },
-            None => return None,
+        self.try_key(name).unwrap_or(None)
+    }
+
+    /// Retrieves key content following aliases. Returns `None` when the key
+    /// is missing and errors when the tree can not be read.
+    pub fn try_key(&self, name: &str) -> Result<Option<Vec<u8>>, GitmapError> {
+        let started = self.slow_op_start();
+        let name = &*self.key_name(name);
+        let value = (|| {
+            let tree_id = self.read_tree_id()?;
+            if self.cached_miss(tree_id, name) == Some(true) {
+                return Ok(None);
+            }
+            let value = self.try_tree_value(tree_id, name)?;
+            if value.is_none() {
+                self.cache_miss(tree_id, name, true);
+            }
+            Ok(value)
+        })();
+        self.report_slow_op(started, "key", Some(name), value.as_ref().map_or(0, |value| value.as_ref().map_or(0, Vec::len)));
+        value
+    }
+
+    /// Returns the value size of the key in bytes, including staged changes,
+    /// without loading the value. Returns `None` when the key is missing.
+    pub fn key_size(&self, name: &str) -> Option<usize> {
+        let id = self.key_blob_id(name).ok()??;
+        Some(self.repo.odb().ok()?.read_header(id).ok()?.0)
+    }
+
+    /// Retrieves up to `len` bytes of key content starting at `offset`,
+    /// including staged changes. The range is cut at the end of the value so
+    /// an offset past it returns an empty value. Returns `None` when the key
+    /// is missing. Loose values are streamed so only the range is held in
+    /// memory.
+    pub fn key_range(&self, name: &str, offset: usize, len: usize) -> Option<Vec<u8>> {
+        let id = self.key_blob_id(name).ok()??;
+        if let Ok((mut reader, size, _)) = self.repo.odb().ok()?.reader(id) {
+            // the stream reports every read as complete, so reads never ask
+            // for more than the rest of the value
+            let (start, end) = (offset.min(size), offset.saturating_add(len).min(size));
+            let mut range = Vec::with_capacity(end - start);
+            let mut chunk = vec![0; RANGE_CHUNK_SIZE];
+            let mut position = 0;
+            while position < end {
+                let read = (end - position).min(chunk.len());
+                reader.read_exact(&mut chunk[..read]).ok()?;
+                if position + read > start {
+                    range.extend_from_slice(&chunk[start.saturating_sub(position)..read]);
+                }
+                position += read;
+            }
+            return Some(range);
+        }
+        let blob = self.repo.find_blob(id).ok()?;
+        let content = blob.content();
+        let start = offset.min(content.len());
+        let end = start.saturating_add(len).min(content.len());
+        Some(content[start..end].to_vec())
+    }
+
+    /// Returns a reader of key content, including staged changes, which can
+    /// be streamed with `std::io::copy` without copying the whole value. The
+    /// reader borrows the repository. Returns `None` when the key is missing.
+    pub fn key_reader(&self, name: &str) -> Option<impl Read + '_> {
+        let blob = self.repo.find_blob(self.key_blob_id(name).ok()??).ok()?;
+        Some(BlobReader { blob, position: 0 })
+    }
+
+    /// Returns the blob ID of the key following aliases or `None` when the key
+    /// is missing.
+    fn key_blob_id(&self, name: &str) -> Result<Option<Oid>, GitmapError> {
+        let tree_id = self.read_tree_id()?;
+        let name = match self.resolve_alias(tree_id, &self.key_name(name))? {
+            Some(name) => name,
+            None => return Ok(None),
         };
-        Some(content)
+        Ok(blob_id(&self.repo.find_tree(tree_id)?, &name))
+    }
+
+    /// Retrieves key content as a string. Returns `None` when the key is
+    /// missing or its value is not valid UTF-8.
+    pub fn key_str(&self, name: &str) -> Option<String> {
+        self.key(name).and_then(|value| String::from_utf8(value).ok())
+    }
+
+    /// Retrieves key content at the provided revision which can be anything
+    /// git understands like a commit ID, a branch name or `HEAD~2`. Returns
+    /// `None` when the revision or the key does not exist.
+    pub fn key_at(&self, name: &str, rev: &str) -> Option<Vec<u8>> {
+        let name = &*self.key_name(name);
+        match self.rev_tree_id(rev) {
+            Ok(id) => self.tree_value(id, name),
+            Err(_) => None,
+        }
+    }
+
+    /// Retrieves key content as it was in the provided commit. Returns `None`
+    /// when the commit or the key does not exist.
+    pub fn key_at_commit(&self, name: &str, commit: Oid) -> Option<Vec<u8>> {
+        let name = &*self.key_name(name);
+        match self.repo.find_commit(commit) {
+            Ok(commit) => self.tree_value(commit.tree_id(), name),
+            Err(_) => None,
+        }
+    }
+
+    /// Retrieves key content committed on the provided branch without
+    /// switching to it. Returns `None` when the branch or key is missing.
+    pub fn key_on_branch(&self, branch: &str, name: &str) -> Option<Vec<u8>> {
+        let name = &*self.key_name(name);
+        match self.branch_tree_id(branch) {
+            Ok(id) => self.tree_value(id, name),
+            Err(_) => None,
+        }
+    }
+
+    /// List keys at the provided revision.
+    pub fn keys_at(&self, rev: &str) -> Result<Vec<String>, GitmapError> {
+        self.tree_keys(self.rev_tree_id(rev)?)
+    }
+
+    /// Retrieves the staged key content which is the value the next commit
+    /// will store. When nothing is staged the committed value is returned.
+    pub fn staged_key(&self, name: &str) -> Option<Vec<u8>> {
+        let name = &*self.key_name(name);
+        match self.tree_id {
+            Some(id) => self.tree_value(id, name),
+            None => self.key(name),
+        }
     }
     
     /// Ensures new working branch. There must be at least one commit in the
     /// repository for this method to work other wise the error is thrown.
-    pub fn switch_branch(&mut self, name: &str) -> Result<(), Error> {
+    pub fn switch_branch(&mut self, name: &str) -> Result<(), GitmapError> {
         if !self.has_branch(name) {
             let commit = self.repo.find_commit(self.last_commit_id()?)?;
-            self.repo.branch(name, &commit, false)?;
+            self.retry_locked(|| Ok(self.repo.branch(name, &commit, false).map(|_| ())?))?;
         }
-        self.repo.set_head(
+        self.retry_locked(|| Ok(self.repo.set_head(
             format!("refs/heads/{}", name).as_str(),
-        )?;
+        )?))?;
+        if self.tree_id.is_some() {
+            self.staged_base.set(self.branch_tip_id()?);
+        }
         Ok(())
     }
 
     /// Removes working branch. Note that the current branch can not be removed
     /// and you have to first switch to a new branch.
-    pub fn remove_branch(&mut self, name: &str) -> Result<(), Error> {
-        self.repo.find_branch(&name, BranchType::Local)?.delete()
+    pub fn remove_branch(&mut self, name: &str) -> Result<(), GitmapError> {
+        Ok(self.repo.find_branch(name, BranchType::Local)?.delete()?)
     }
 
-    /// Stages key for commit.
-    pub fn insert_key(&mut self, name: &str, value: &[u8]) -> Result<(), Error> {
-        let tree = self.repo.find_tree(self.current_tree_id()?)?;
-        let file_oid = self.repo.blob(value)?;
-        let mut builder = self.repo.treebuilder(Some(&tree))?;
-        builder.insert(name, file_oid, 0o100644)?;
-        self.tree_id = Some(builder.write()?);
-        Ok(())
+    /// Creates a lightweight tag at the tip of the working branch. Revision
+    /// based reads like `key_at` accept tag names. Errors without commits or
+    /// when the tag exists.
+    pub fn tag(&mut self, name: &str) -> Result<(), GitmapError> {
+        let commit = self.repo.find_commit(self.last_commit_id()?)?;
+        self.retry_locked(|| Ok(self.repo.tag_lightweight(name, commit.as_object(), false).map(|_| ())?))
     }
 
-    /// Reset all keys.
-    pub fn reset(&mut self) -> Result<(), Error> {
-        self.tree_id = None;
-        Ok(())
+    /// Creates an annotated tag at the tip of the working branch signed with
+    /// the commit identity and returns the tag object ID. Errors without
+    /// commits or when the tag exists.
+    pub fn tag_with_message(&mut self, name: &str, message: &str) -> Result<Oid, GitmapError> {
+        let commit = self.repo.find_commit(self.last_commit_id()?)?;
+        let sig = self.signature()?;
+        self.retry_locked(|| Ok(self.repo.tag(name, commit.as_object(), &sig, message, false)?))
     }
 
-    /// Remove all keys.
-    pub fn remove(&mut self) -> Result<(), Error> {
-        let tree = self.repo.find_tree(self.current_tree_id()?)?;
-        let mut builder = self.repo.treebuilder(Some(&tree))?;
-        for key in self.keys() {
-            builder.remove(key)?;
+    /// List tag names.
+    pub fn tags(&self) -> Vec<String> {
+        match self.repo.tag_names(None) {
+            Ok(names) => names.iter().flatten().map(String::from).collect(),
+            Err(_) => Vec::new(),
         }
-        self.tree_id = Some(builder.write()?);
-        Ok(())
     }
 
-    /// Returns true if any key has been changed.
-    pub fn changed(&self) -> bool {
-        if !self.has_commits() {
-            return self.tree_id.is_some() && self.len() > 0;
-        }
-        let mut opts = DiffOptions::new();
-        let old_tree = match self.last_tree_id() {
-            Ok(id) => match self.repo.find_tree(id) {
-                Ok(tree) => tree,
-                Err(_) => return false,
-            },
-            Err(_) => return false,
+    /// Returns true if the tag exists.
+    pub fn has_tag(&self, name: &str) -> bool {
+        self.tags().contains(&name.to_string())
+    }
+
+    /// Removes the tag. Errors when it does not exist.
+    pub fn remove_tag(&mut self, name: &str) -> Result<(), GitmapError> {
+        Ok(self.repo.tag_delete(name)?)
+    }
+
+    /// Switches to the branch, creating it when missing, runs `f` and commits
+    /// the changes it stages there. HEAD and the staged changes of the current
+    /// branch are restored afterwards. On error the branch is restored as well
+    /// or removed when this call created it. Returns the new commit ID.
+    pub fn deploy_to_branch<F>(&mut self, branch: &str, message: &str, f: F) -> Result<Oid, GitmapError>
+    where
+        F: FnOnce(&mut Self) -> Result<(), GitmapError>,
+    {
+        let (head_name, head_id) = {
+            let head = self.repo.find_reference("HEAD")?;
+            (head.symbolic_target().map(String::from), head.target())
         };
-        let new_tree = match self.current_tree_id() {
-            Ok(id) => match self.repo.find_tree(id) {
-                Ok(tree) => tree,
-                Err(_) => return false,
-            },
-            Err(_) => return false,
+        let tip = match self.repo.find_branch(branch, BranchType::Local) {
+            Ok(branch) => Some(branch.get().peel_to_commit()?.id()),
+            Err(_) => None,
         };
-        let diff = match self.repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), Some(&mut opts)) {
-            Ok(diff) => diff,
-            Err(_) => return false,
+        let staged = (self.tree_id, self.staged_base.get(), self.staged_keys.take());
+        self.set_staged_tree(None);
+        let result = self.switch_branch(branch)
+            .and_then(|_| f(self))
+            .and_then(|_| self.commit(message));
+        let restored = match (&head_name, head_id) {
+            (Some(name), _) => self.repo.set_head(name),
+            (None, Some(id)) => self.repo.set_head_detached(id),
+            (None, None) => Ok(()),
         };
-        diff.deltas().len() > 0
+        if result.is_err() {
+            let _ = match tip {
+                Some(id) => self.repo.find_branch(branch, BranchType::Local)
+                    .and_then(|mut b| b.get_mut().set_target(id, "deploy rollback").map(|_| ()))
+                    .map_err(GitmapError::from),
+                None => self.remove_branch(branch),
+            };
+            self.tree_id = staged.0;
+            self.staged_base.set(staged.1);
+            self.staged_keys.replace(staged.2);
+        } else if head_name.as_deref() != Some(&format!("refs/heads/{}", branch)) {
+            self.tree_id = staged.0;
+            self.staged_base.set(staged.1);
+            self.staged_keys.replace(staged.2);
+        }
+        let id = result?;
+        restored?;
+        Ok(id)
     }
 
-    /// Commits data.
-    pub fn commit(&self, message: &str) -> Result<(), Error> {
-        let tree_id = self.current_tree_id()?;
-        let tree = self.repo.find_tree(tree_id)?;
-        let sig = self.repo.signature()?;
-        if !self.has_commits() {
-            self.repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &[])?;
-        } else {
-            let commit = self.repo.find_commit(self.last_commit_id()?)?;
-            self.repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &[&commit])?;
-        }
-        Ok(())
+    /// Stages key for commit. Slash-separated names like `config/app/theme`
+    /// are stored in nested trees which are created as needed.
+    pub fn insert_key(&mut self, name: &str, value: &[u8]) -> Result<(), GitmapError> {
+        let started = self.slow_op_start();
+        let name = &*self.key_name(name);
+        let result = check_name(name)
+            .and_then(|_| self.check_value(name, value))
+            .and_then(|_| self.write_blob(value))
+            .and_then(|blob| self.stage_changes(&[(name, Some(blob))]));
+        self.report_slow_op(started, "insert_key", Some(name), value.len());
+        result
     }
 
-    /// Stages key for removal.
-    pub fn reset_key(&mut self, name: &str) -> Result<(), Error> {
-        let tree = self.repo.find_tree(self.current_tree_id()?)?;
-        let mut builder = self.repo.treebuilder(Some(&tree))?;
-        if self.has_key(name) {
-            builder.remove(name)?;
-        }
-        if self.has_commits() {
-            let tree = self.repo.find_tree(self.last_tree_id()?)?;
-            if tree.get_name(name) != None {
-                let entry = tree.get_path(Path::new(name))?;
-                let blob = entry.to_object(&self.repo)?;
-                let blob = blob.as_blob().unwrap();
-                let oid = self.repo.blob(&blob.content())?;
-                builder.insert(name, oid, 0o100644)?;
+    /// Stages the value read from the reader like `insert_key`. The value is
+    /// streamed into the object database so it is never held in memory as a
+    /// whole. Reader errors are returned and the partial value is discarded.
+    pub fn insert_key_from<R: Read>(&mut self, name: &str, mut reader: R) -> Result<(), GitmapError> {
+        let started = self.slow_op_start();
+        let name = &*self.key_name(name);
+        let mut size = 0;
+        let result = check_name(name).and_then(|_| {
+            let mut writer = self.repo.blob_writer(None)?;
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let len = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => len,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(io_error(err)),
+                };
+                size += len;
+                if let Some(max) = self.max_value_size.filter(|max| size > *max) {
+                    return Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, format!("value of `{}` exceeds the maximum size of {} bytes", name, max)));
+                }
+                writer.write_all(&buf[..len]).map_err(io_error)?;
             }
-        }
-        self.tree_id = Some(builder.write()?);
-        Ok(())
+            Ok(writer.commit()?)
+        }).and_then(|blob| self.stage_changes(&[(name, Some(blob))]));
+        self.report_slow_op(started, "insert_key_from", Some(name), size);
+        result
     }
-    
-    /// Stages key for removal.
-    pub fn remove_key(&mut self, name: &str) -> Result<(), Error> {
-        if self.has_key(name) {
-            let tree = self.repo.find_tree(self.current_tree_id()?)?;
-            let mut builder = self.repo.treebuilder(Some(&tree))?;
-            builder.remove(name)?;
-            self.tree_id = Some(builder.write()?);
+
+    /// Stages the content of the file like `insert_key_from`.
+    pub fn insert_key_from_file<P: AsRef<Path>>(&mut self, name: &str, path: P) -> Result<(), GitmapError> {
+        let file = File::open(path).map_err(io_error)?;
+        self.insert_key_from(name, file)
+    }
+
+    /// Stages a string value for commit.
+    pub fn insert_str(&mut self, name: &str, value: &str) -> Result<(), GitmapError> {
+        self.insert_key(name, value.as_bytes())
+    }
+
+    /// Stages the key only when its current value, as returned by `key`,
+    /// matches `expected`, where `None` means the key must not exist. Returns
+    /// false without staging anything on mismatch.
+    pub fn insert_key_if(&mut self, name: &str, expected: Option<&[u8]>, value: &[u8]) -> Result<bool, GitmapError> {
+        if self.try_key(name)?.as_deref() != expected {
+            return Ok(false);
         }
-        Ok(())
+        self.insert_key(name, value)?;
+        Ok(true)
     }
-    
-    /// Returns true if the key content has been changed.
-    pub fn key_changed(&self, name: &str) -> bool {
-        if !self.has_commits() {
-            return self.has_key(name);
+
+    /// Stages the key only when it does not exist, like `insert_key_if` with
+    /// no expected value. Returns false without staging anything when it does.
+    pub fn insert_key_if_absent(&mut self, name: &str, value: &[u8]) -> Result<bool, GitmapError> {
+        self.insert_key_if(name, None, value)
+    }
+
+    /// Stages `new` only when the current value of the key, as returned by
+    /// `key`, matches `expected` like `insert_key_if`. A `new` value of `None`
+    /// removes the key. Returns false without staging anything on mismatch.
+    /// Commits fail when the branch moved meanwhile, so repeating reset, swap
+    /// and commit gives a compare-and-swap loop across processes.
+    pub fn compare_and_swap(&mut self, name: &str, expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool, GitmapError> {
+        if let Some(value) = new {
+            return self.insert_key_if(name, expected, value);
         }
-        let mut opts = DiffOptions::new();
-        let old_tree = match self.last_tree_id() {
-            Ok(id) => match self.repo.find_tree(id) {
-                Ok(tree) => tree,
-                Err(_) => return false,
+        if self.try_key(name)?.as_deref() != expected {
+            return Ok(false);
+        }
+        if expected.is_some() {
+            self.remove_key(name)?;
+        }
+        Ok(true)
+    }
+
+    /// Stages multiple keys at once with a single tree write. Nothing is staged
+    /// when any key fails.
+    pub fn insert_keys<I: IntoIterator<Item = (String, Vec<u8>)>>(&mut self, entries: I) -> Result<(), GitmapError> {
+        let mut changes = BTreeMap::new();
+        for (name, value) in entries {
+            let name = self.key_name(&name).into_owned();
+            check_name(&name)?;
+            self.check_value(&name, &value)?;
+            let blob = self.write_blob(&value)?;
+            changes.insert(name, Some(blob));
+        }
+        let changes: Vec<(&str, Option<Oid>)> = changes.iter().map(|(name, blob)| (name.as_str(), *blob)).collect();
+        self.stage_changes(&changes)
+    }
+
+    /// Stages an alias which makes `key()` of the `alias` name return the
+    /// value of the `target` key. The target must exist and an alias can not
+    /// shadow a real key.
+    pub fn alias_key(&mut self, alias: &str, target: &str) -> Result<(), GitmapError> {
+        let alias = &*self.key_name(alias);
+        let target = &*self.key_name(target);
+        check_name(alias)?;
+        if self.staged_has_key(alias) {
+            return Err(GitmapError::new(ErrorCode::Exists, ErrorClass::Invalid, format!("key `{}` already exists", alias)));
+        }
+        let mut aliases = self.aliases();
+        aliases.retain(|(name, _)| name != alias);
+        if !matches!(self.follow_aliases(self.current_tree_id()?, &aliases, target), Ok(Some(_))) {
+            return Err(GitmapError::new(ErrorCode::NotFound, ErrorClass::Invalid, format!("alias target `{}` does not exist", target)));
+        }
+        aliases.push((alias.to_string(), target.to_string()));
+        aliases.sort();
+        self.set_meta(ALIASES_META, &encode_aliases(&aliases))
+    }
+
+    /// Stages alias for removal.
+    pub fn remove_alias(&mut self, alias: &str) -> Result<(), GitmapError> {
+        let alias = &*self.key_name(alias);
+        let mut aliases = self.aliases();
+        let count = aliases.len();
+        aliases.retain(|(name, _)| name != alias);
+        if aliases.len() != count {
+            self.set_meta(ALIASES_META, &encode_aliases(&aliases))?;
+        }
+        Ok(())
+    }
+
+    /// Stages the value under the next slot of a ring buffer of `capacity`
+    /// keys named `prefix0`, `prefix1`, ... and returns the slot key used.
+    /// Once all slots are used the oldest one is overwritten. The slot and
+    /// the ring position are staged together so commits stay consistent.
+    pub fn insert_rotating(&mut self, prefix: &str, value: &[u8], capacity: usize) -> Result<String, GitmapError> {
+        let prefix = &*self.key_name(prefix);
+        if capacity == 0 {
+            return Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, "rotating keys require a positive capacity"));
+        }
+        let mut rings = self.rings()?;
+        let index = match rings.iter().position(|ring| ring.prefix == prefix) {
+            Some(index) => index,
+            None => {
+                rings.push(Ring { prefix: prefix.to_string(), capacity, next: 0, count: 0 });
+                rings.len() - 1
             },
-            Err(_) => return false,
         };
-        let new_tree = match self.current_tree_id() {
-            Ok(id) => match self.repo.find_tree(id) {
-                Ok(tree) => tree,
-                Err(_) => return false,
-            },
-            Err(_) => return false,
+        let ring = &mut rings[index];
+        if ring.capacity != capacity {
+            return Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, format!("rotating keys `{}` require capacity {}", prefix, ring.capacity)));
+        }
+        let name = format!("{}{}", prefix, ring.next);
+        check_name(&name)?;
+        self.check_value(&name, value)?;
+        ring.next = (ring.next + 1) % capacity;
+        ring.count = capacity.min(ring.count + 1);
+        let blob = self.write_blob(value)?;
+        let meta = self.write_blob(&encode_rings(&rings))?;
+        self.stage_changes(&[(&name, Some(blob)), (ROTATING_META, Some(meta))])?;
+        Ok(name)
+    }
+
+    /// List slot keys written by `insert_rotating` from oldest to newest.
+    pub fn rotating_entries(&self, prefix: &str) -> Vec<String> {
+        self.try_rotating_entries(prefix).unwrap_or_default()
+    }
+
+    /// List slot keys written by `insert_rotating` from oldest to newest.
+    /// Errors when the rotating key state is corrupt.
+    pub fn try_rotating_entries(&self, prefix: &str) -> Result<Vec<String>, GitmapError> {
+        let prefix = &*self.key_name(prefix);
+        let ring = match self.rings()?.into_iter().find(|ring| ring.prefix == prefix) {
+            Some(ring) => ring,
+            None => return Ok(Vec::new()),
         };
-        let diff = match self.repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), Some(&mut opts)) {
-            Ok(diff) => diff,
-            Err(_) => return false,
+        let first = if ring.count < ring.capacity { 0 } else { ring.next };
+        Ok((0..ring.count)
+            .map(|i| format!("{}{}", prefix, (first + i) % ring.capacity))
+            .collect())
+    }
+
+    /// Reset all keys.
+    pub fn reset(&mut self) -> Result<(), GitmapError> {
+        self.set_staged_tree(None);
+        Ok(())
+    }
+
+    /// Remove all keys together with aliases and rotating key state. An empty
+    /// tree is staged so memory use does not grow with the number of keys.
+    pub fn remove(&mut self) -> Result<(), GitmapError> {
+        let tree_id = self.empty_tree_id()?;
+        self.set_staged_tree(Some(tree_id));
+        Ok(())
+    }
+
+    /// Calls `f` with the path and the entry of every key, including staged
+    /// changes, until it breaks. Keys are streamed from the tree so memory use
+    /// does not grow with the number of keys.
+    pub(crate) fn for_each_entry<F>(&self, mut f: F) -> Result<(), GitmapError>
+    where
+        F: FnMut(&str, &TreeEntryLite) -> ControlFlow<()>,
+    {
+        let tree = self.repo.find_tree(self.read_tree_id()?)?;
+        let mut path = String::new();
+        let mut stopped = false;
+        let walked = tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            let (name, kind) = match (entry.name(), entry.kind()) {
+                (Some(name), Some(ObjectType::Blob)) => (name, EntryKind::Blob),
+                (Some(name), kind) if self.include_foreign && kind != Some(ObjectType::Tree) => (name, EntryKind::Other),
+                _ => return TreeWalkResult::Ok,
+            };
+            if dir.is_empty() && is_meta(name) {
+                return TreeWalkResult::Ok;
+            }
+            path.clear();
+            path.push_str(dir);
+            path.push_str(name);
+            let lite = TreeEntryLite { id: entry.id(), filemode: entry.filemode(), kind };
+            match f(&path, &lite) {
+                ControlFlow::Continue(_) => TreeWalkResult::Ok,
+                ControlFlow::Break(_) => {
+                    stopped = true;
+                    TreeWalkResult::Abort
+                },
+            }
+        });
+        match walked {
+            Err(_) if stopped => Ok(()),
+            res => Ok(res?),
+        }
+    }
+
+    /// Returns true if any key has been changed.
+    pub fn changed(&self) -> bool {
+        self.try_changed().unwrap_or(false)
+    }
+
+    /// Returns the number of keys changed since the last commit without
+    /// listing them. Without commits every staged key counts.
+    pub fn changed_count(&self) -> usize {
+        if !self.has_commits() {
+            return match self.tree_id {
+                Some(id) => self.tree_len(id),
+                None => 0,
+            };
+        }
+        match self.staged_diff() {
+            Ok(diff) => diff.deltas().filter(|delta| !is_meta_delta(delta)).count(),
+            Err(_) => 0,
+        }
+    }
+
+    /// Returns true if any key has been changed. Alias changes alone do not
+    /// count, like in `changed_count`. Errors when the trees can not be
+    /// compared.
+    pub fn try_changed(&self) -> Result<bool, GitmapError> {
+        if !self.has_commits() {
+            return Ok(self.tree_id.is_some() && !self.tree_keys(self.current_tree_id()?)?.is_empty());
+        }
+        Ok(self.staged_diff()?.deltas().any(|delta| !is_meta_delta(&delta)))
+    }
+
+    /// Returns true if the staged tree differs from the last commit, also
+    /// when only aliases or rotating key state changed.
+    pub(crate) fn tree_changed(&self) -> Result<bool, GitmapError> {
+        match self.has_commits() {
+            true => Ok(self.current_tree_id()? != self.last_tree_id()?),
+            false => self.try_changed(),
+        }
+    }
+
+    /// List staged key changes against the last commit, similar to `git
+    /// status`. Without commits every staged key is added.
+    pub fn staged_changes(&self) -> Result<Vec<KeyChange>, GitmapError> {
+        let old_tree = match self.has_commits() {
+            true => Some(self.repo.find_tree(self.last_tree_id()?)?),
+            false => None,
         };
+        let new_tree = self.repo.find_tree(self.current_tree_id()?)?;
+        self.key_changes(old_tree.as_ref(), &new_tree)
+    }
+
+    /// List keys of branch `b` which are added, modified or removed relative
+    /// to branch `a`. Errors when either branch does not exist.
+    pub fn diff_branches(&self, a: &str, b: &str) -> Result<Vec<KeyChange>, GitmapError> {
+        let old_tree = self.repo.find_tree(self.branch_tree_id(a)?)?;
+        let new_tree = self.repo.find_tree(self.branch_tree_id(b)?)?;
+        self.key_changes(Some(&old_tree), &new_tree)
+    }
+
+    /// List key changes between the trees.
+    fn key_changes(&self, old_tree: Option<&Tree>, new_tree: &Tree) -> Result<Vec<KeyChange>, GitmapError> {
+        let diff = self.repo.diff_tree_to_tree(old_tree, Some(new_tree), None)?;
+        let mut changes = Vec::new();
         for delta in diff.deltas() {
-            match delta.new_file().path() {
-                Some(path) => match path.to_str() {
-                    Some(n) => match n == name {
-                        true => return true,
-                        false => continue,
-                    },
-                    None => continue,
+            let file = match delta.status() {
+                Delta::Deleted => delta.old_file(),
+                _ => delta.new_file(),
+            };
+            let name = match file.path().and_then(|p| p.to_str()) {
+                Some(name) if !is_meta(name) => name.to_string(),
+                _ => continue,
+            };
+            changes.push(match delta.status() {
+                Delta::Added => KeyChange::Added(name),
+                Delta::Deleted => KeyChange::Removed(name),
+                Delta::Modified | Delta::Typechange => KeyChange::Modified(name),
+                _ => continue,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// Reports how much of the staged data is new compared to the last
+    /// commit. Sizes are read from object headers so values are not loaded.
+    pub fn stage_report(&self) -> Result<StageReport, GitmapError> {
+        let staged = self.repo.find_tree(self.current_tree_id()?)?;
+        let committed = match self.has_commits() {
+            true => Some(self.repo.find_tree(self.last_tree_id()?)?),
+            false => None,
+        };
+        let mut known = HashSet::new();
+        if let Some(tree) = &committed {
+            tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+                if entry.kind() == Some(ObjectType::Blob) {
+                    known.insert(entry.id());
+                }
+                TreeWalkResult::Ok
+            })?;
+        }
+        let mut names = self.staged_keys.borrow().clone();
+        for delta in self.repo.diff_tree_to_tree(committed.as_ref(), Some(&staged), None)?.deltas() {
+            if delta.status() != Delta::Deleted {
+                names.extend(delta.new_file().path().and_then(|p| p.to_str()).map(String::from));
+            }
+        }
+        let odb = self.repo.odb()?;
+        let mut report = StageReport::default();
+        for name in names.iter().filter(|name| !is_meta(name)) {
+            let id = match staged.get_path(Path::new(name)) {
+                Ok(entry) if entry.kind() == Some(ObjectType::Blob) => entry.id(),
+                _ => continue,
+            };
+            let old = committed.as_ref().and_then(|tree| tree.get_path(Path::new(name)).ok()).map(|entry| entry.id());
+            if old == Some(id) {
+                report.unchanged += 1;
+            } else if !known.insert(id) {
+                report.dedup_hits += 1;
+            } else {
+                report.new_blobs += 1;
+                report.new_bytes += odb.read_header(id)?.0 as u64;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Counts keys, including staged changes, per value size bucket. Bounds
+    /// are inclusive upper limits in bytes. Sizes are read from object headers
+    /// in a single tree walk so values are not loaded.
+    pub fn value_size_histogram(&self, buckets: &[u64]) -> Result<SizeHistogram, GitmapError> {
+        let mut bounds = buckets.to_vec();
+        bounds.sort_unstable();
+        bounds.dedup();
+        if bounds.last() != Some(&u64::MAX) {
+            bounds.push(u64::MAX);
+        }
+        let mut histogram = SizeHistogram { buckets: bounds.into_iter().map(|bound| (bound, 0)).collect(), ..Default::default() };
+        if !self.has_commits() && self.tree_id.is_none() {
+            return Ok(histogram);
+        }
+        let odb = self.repo.odb()?;
+        let mut failure = None;
+        self.for_each_entry(|_, entry| {
+            let size = match odb.read_header(entry.id) {
+                Ok((size, _)) => size as u64,
+                Err(err) => {
+                    failure = Some(err);
+                    return ControlFlow::Break(());
                 },
-                None => continue,
             };
+            let index = histogram.buckets.partition_point(|(bound, _)| *bound < size);
+            histogram.buckets[index].1 += 1;
+            if size == 0 {
+                histogram.zero_sized += 1;
+            }
+            histogram.max_size = histogram.max_size.max(size);
+            ControlFlow::Continue(())
+        })?;
+        match failure {
+            Some(err) => Err(err.into()),
+            None => Ok(histogram),
+        }
+    }
+
+    /// Collects store metrics for monitoring. Keys and value sizes include
+    /// staged changes and are counted in one tree walk reading object headers
+    /// only, commits are counted on the working branch.
+    pub fn metrics(&self) -> Result<StoreMetrics, GitmapError> {
+        let mut metrics = StoreMetrics { branches: self.try_branches()?.len(), ..Default::default() };
+        if !self.has_commits() && self.tree_id.is_none() {
+            return Ok(metrics);
+        }
+        let odb = self.repo.odb()?;
+        let mut failure = None;
+        self.for_each_entry(|_, entry| {
+            if entry.kind != EntryKind::Blob {
+                return ControlFlow::Continue(());
+            }
+            match odb.read_header(entry.id) {
+                Ok((size, _)) => {
+                    metrics.keys += 1;
+                    metrics.value_bytes += size as u64;
+                    ControlFlow::Continue(())
+                },
+                Err(err) => {
+                    failure = Some(err);
+                    ControlFlow::Break(())
+                },
+            }
+        })?;
+        if let Some(err) = failure {
+            return Err(err.into());
+        }
+        if self.has_commits() {
+            let head = self.repo.find_commit(self.last_commit_id()?)?;
+            metrics.last_commit_time = Some(head.time().seconds());
+            let mut walk = self.repo.revwalk()?;
+            walk.push(head.id())?;
+            metrics.commits = walk.count();
+        }
+        Ok(metrics)
+    }
+
+    /// Commits data and returns the new commit ID. Errors when the branch
+    /// moved, for example by another process, since changes were staged.
+    pub fn commit(&self, message: &str) -> Result<Oid, GitmapError> {
+        self.commit_signed(message, &self.signature()?)
+    }
+
+    /// Commits data using the provided name and email as the author and
+    /// committer. In deterministic mode the forced identity is used instead.
+    pub fn commit_with_author(&self, message: &str, name: &str, email: &str) -> Result<Oid, GitmapError> {
+        let sig = match self.deterministic {
+            Some(_) => self.signature()?,
+            None => Signature::now(name, email)?,
+        };
+        self.commit_signed(message, &sig)
+    }
+
+    /// Commits data recording the provided UTC offset in minutes, like 0 for
+    /// UTC or 120 for UTC+2, instead of the local one.
+    pub fn commit_with_offset(&self, message: &str, offset_minutes: i32) -> Result<Oid, GitmapError> {
+        let sig = self.signature()?;
+        let time = Time::new(sig.when().seconds(), offset_minutes);
+        let sig = Signature::new(&String::from_utf8_lossy(sig.name_bytes()), &String::from_utf8_lossy(sig.email_bytes()), &time)?;
+        self.commit_signed(message, &sig)
+    }
+
+    /// Commits data like `commit` and returns the number of added, modified
+    /// and removed keys compared to the previous commit.
+    pub fn commit_detailed(&self, message: &str) -> Result<CommitResult, GitmapError> {
+        let changes = self.staged_changes()?;
+        let is_initial = !self.has_commits();
+        let oid = self.commit(message)?;
+        let mut result = CommitResult { oid, added: 0, modified: 0, removed: 0, is_initial };
+        for change in changes {
+            match change {
+                KeyChange::Added(_) => result.added += 1,
+                KeyChange::Modified(_) => result.modified += 1,
+                KeyChange::Removed(_) => result.removed += 1,
+            }
+        }
+        Ok(result)
+    }
+
+    /// Commits data with the provided signature.
+    fn commit_signed(&self, message: &str, sig: &Signature) -> Result<Oid, GitmapError> {
+        self.commit_tree(self.current_tree_id()?, message, sig)
+    }
+
+    /// Commits the provided tree on top of HEAD.
+    fn commit_tree(&self, tree_id: Oid, message: &str, sig: &Signature) -> Result<Oid, GitmapError> {
+        let started = self.slow_op_start();
+        let id = self.commit_tree_unreported(tree_id, message, sig);
+        self.report_slow_op(started, "commit", None, 0);
+        id
+    }
+
+    /// Commits the provided tree on top of HEAD without reporting slowness.
+    fn commit_tree_unreported(&self, tree_id: Oid, message: &str, sig: &Signature) -> Result<Oid, GitmapError> {
+        let tip = self.branch_tip_id()?;
+        if self.tree_id.is_some() && self.staged_base.get() != tip {
+            return Err(GitmapError::new(ErrorCode::Modified, ErrorClass::Reference, "branch moved since changes were staged"));
+        }
+        self.commit_tree_to("HEAD", tip.as_slice(), tree_id, message, sig)
+    }
+
+    /// Commits the provided tree on top of the parents, moves the reference to
+    /// the new commit and marks staged changes as committed.
+    pub(crate) fn commit_tree_to(&self, refname: &str, parents: &[Oid], tree_id: Oid, message: &str, sig: &Signature) -> Result<Oid, GitmapError> {
+        let tree = self.repo.find_tree(tree_id)?;
+        #[cfg(feature = "digest")]
+        let message = &self.digest_message(message, tree_id)?;
+        let parents = parents.iter().map(|id| self.repo.find_commit(*id)).collect::<Result<Vec<_>, _>>()?;
+        let parents: Vec<&Commit> = parents.iter().collect();
+        let id = self.retry_locked(|| Ok(self.repo.commit(Some(refname), sig, sig, message, &tree, &parents)?))?;
+        self.staged_base.set(Some(id));
+        self.staged_keys.borrow_mut().clear();
+        Ok(id)
+    }
+
+    /// Runs the ref update again while it fails on a lock held by another
+    /// process, doubling the delay between attempts.
+    fn retry_locked<T, F: FnMut() -> Result<T, GitmapError>>(&self, mut f: F) -> Result<T, GitmapError> {
+        let (retries, mut delay) = self.lock_retries;
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(err) if err.code() == ErrorCode::Locked && attempt < retries => {
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                },
+                res => return res,
+            }
+        }
+    }
+
+    /// Enables case-insensitive mode in which key names are normalized to
+    /// lowercase before they are used. Keys are case-sensitive by default on
+    /// every platform since they live in git trees, not on the filesystem.
+    pub fn set_case_insensitive(&mut self, enabled: bool) {
+        self.case_insensitive = enabled;
+    }
+
+    /// Enables strict committed reads mode in which `key`, `has_key`, `keys`,
+    /// `len`, iterators and exports ignore staged changes and only serve the
+    /// last commit. `staged_key` and `staged_changes` still report staged
+    /// data. Disabled by default.
+    pub fn set_strict_committed_reads(&mut self, enabled: bool) {
+        self.strict_committed_reads = enabled;
+    }
+
+    /// Sets whether `keys` and iterators also list foreign
+    /// tree entries written by other tools, like submodule commits. Such
+    /// entries have no value, are kept by commits and can not be changed.
+    /// Disabled by default.
+    pub fn set_include_foreign(&mut self, enabled: bool) {
+        self.include_foreign = enabled;
+    }
+
+    /// Sets the author and committer of new commits. Without it the identity
+    /// comes from the git config and falls back to `gitmap <gitmap@localhost>`.
+    pub fn set_signature(&mut self, name: &str, email: &str) {
+        self.identity = Some((name.to_string(), email.to_string()));
+    }
+
+    /// Configures how ref updates of `commit` and `switch_branch` which fail
+    /// on a lock held by another process are retried. The update is attempted
+    /// `retries` more times, sleeping `backoff` before the first retry and
+    /// doubling the delay after each one. The last error is returned once
+    /// retries run out. Defaults to 3 retries with a 10ms backoff and 0
+    /// retries disables retrying.
+    pub fn set_lock_retries(&mut self, retries: usize, backoff: Duration) {
+        self.lock_retries = (retries, backoff);
+    }
+
+    /// Limits the size of staged values to the provided number of bytes.
+    /// Inserting a larger value errors. Values are unlimited by default.
+    pub fn set_max_value_size(&mut self, bytes: usize) {
+        self.max_value_size = Some(bytes);
+    }
+
+    /// Enables remembering names missing from the current tree so repeated
+    /// `key` and `has_key` lookups of them skip the tree. Any change of the
+    /// tree invalidates the cache. Disabled by default.
+    pub fn set_negative_cache(&mut self, enabled: bool) {
+        self.negative_cache = match enabled {
+            true => Some(RefCell::new((Oid::zero(), HashMap::new()))),
+            false => None,
+        };
+    }
+
+    /// Forgets all names remembered by the negative cache.
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.negative_cache {
+            cache.borrow_mut().1.clear();
+        }
+    }
+
+    /// Enables deterministic mode in which every commit uses the provided
+    /// identity and `epoch` (seconds, UTC) as the author and committer. Staging
+    /// identical content then always produces identical commit IDs.
+    pub fn set_deterministic(&mut self, epoch: i64, identity: Signature) {
+        self.deterministic = Some((
+            String::from_utf8_lossy(identity.name_bytes()).to_string(),
+            String::from_utf8_lossy(identity.email_bytes()).to_string(),
+            epoch,
+        ));
+    }
+
+    /// Commits data but gives up when the `timeout` passes first. The commit
+    /// runs on a helper thread through a separate repository handle which is
+    /// leaked on timeout, so the commit may still land afterwards.
+    pub fn commit_with_timeout(&self, message: &str, timeout: Duration) -> Result<Oid, GitmapError> {
+        let path = self.path().to_owned();
+        let tree_id = self.tree_id;
+        let staged_base = self.staged_base.get();
+        let deterministic = self.deterministic.clone();
+        let identity = self.identity.clone();
+        let lock_retries = self.lock_retries;
+        #[cfg(feature = "digest")]
+        let tamper_evidence = self.tamper_evidence;
+        let message = message.to_string();
+        with_timeout(timeout, move || {
+            let mut repo = Self::open(path)?;
+            repo.tree_id = tree_id;
+            repo.staged_base.set(staged_base);
+            repo.deterministic = deterministic;
+            repo.identity = identity;
+            repo.lock_retries = lock_retries;
+            #[cfg(feature = "digest")]
+            {
+                repo.tamper_evidence = tamper_evidence;
+            }
+            repo.commit(&message)
+        }).inspect(|id| {
+            if self.tree_id.is_some() {
+                self.staged_base.set(Some(*id));
+            }
+            self.staged_keys.borrow_mut().clear();
+        })
+    }
+
+    /// Stages key for removal.
+    pub fn reset_key(&mut self, name: &str) -> Result<(), GitmapError> {
+        let name = &*self.key_name(name);
+        if is_meta(name) {
+            return Ok(());
+        }
+        let committed = match self.has_commits() {
+            true => match self.repo.find_tree(self.last_tree_id()?)?.get_path(Path::new(name)) {
+                Ok(entry) if entry.kind() == Some(ObjectType::Blob) => Some(entry.id()),
+                _ => None,
+            },
+            false => None,
+        };
+        self.stage_changes(&[(name, committed)])
+    }
+    
+    /// Restores the keys to their last committed values in one tree update.
+    /// Keys missing from both staged and committed state are ignored.
+    pub fn reset_keys(&mut self, names: &[&str]) -> Result<(), GitmapError> {
+        let names: Vec<String> = names.iter()
+            .map(|name| self.key_name(name).into_owned())
+            .filter(|name| !is_meta(name))
+            .collect();
+        let blobs: Vec<Option<Oid>> = match self.has_commits() {
+            true => {
+                let tree = self.repo.find_tree(self.last_tree_id()?)?;
+                names.iter().map(|name| blob_id(&tree, name)).collect()
+            },
+            false => vec![None; names.len()],
+        };
+        let changes: Vec<(&str, Option<Oid>)> = names.iter().map(String::as_str).zip(blobs).collect();
+        self.stage_changes(&changes)
+    }
+
+    /// Stages the value the key had at the provided revision, re-creating it
+    /// when it has been removed since. Errors when the revision does not exist
+    /// or does not contain the key.
+    pub fn restore_key(&mut self, name: &str, rev: &str) -> Result<(), GitmapError> {
+        let name = &*self.key_name(name);
+        let blob = match blob_id(&self.repo.find_tree(self.rev_tree_id(rev)?)?, name) {
+            Some(id) if !is_meta(name) => id,
+            _ => return Err(GitmapError::NotFound(format!("key `{}` does not exist at `{}`", name, rev))),
+        };
+        self.stage_changes(&[(name, Some(blob))])
+    }
+
+    /// Stages the whole tree of the provided revision, including aliases, so
+    /// the next commit reverts the store to that state.
+    pub fn restore_all(&mut self, rev: &str) -> Result<(), GitmapError> {
+        let tree_id = self.rev_tree_id(rev)?;
+        self.set_staged_tree(Some(tree_id));
+        Ok(())
+    }
+
+    /// Discards staged changes to this key, restoring the committed value.
+    ///
+    /// ```
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let mut repo = gitmap::Repo::init(dir.path()).unwrap();
+    /// repo.insert_key("foo", b"1").unwrap();
+    /// repo.commit("initial").unwrap();
+    /// repo.insert_key("foo", b"2").unwrap();
+    /// repo.unstage_key("foo").unwrap();
+    /// assert_eq!(repo.key("foo").unwrap(), b"1");
+    /// ```
+    pub fn unstage_key(&mut self, name: &str) -> Result<(), GitmapError> {
+        self.reset_key(name)
+    }
+
+    /// Stages key for removal. Nested trees left empty are removed as well.
+    pub fn remove_key(&mut self, name: &str) -> Result<(), GitmapError> {
+        let started = self.slow_op_start();
+        let name = &*self.key_name(name);
+        let result = self.current_tree_id().and_then(|tree_id| {
+            match self.staged_has_key(name) || self.repo.find_tree(tree_id).map(|tree| foreign_kind(&tree, name).is_some()).unwrap_or(false) {
+                true => self.stage_changes(&[(name, None)]),
+                false => Ok(()),
+            }
+        });
+        self.report_slow_op(started, "remove_key", Some(name), 0);
+        result
+    }
+    
+    /// Stages removal of all keys starting with the provided prefix with a
+    /// single tree write. Returns the number of removed keys.
+    pub fn remove_keys_with_prefix(&mut self, prefix: &str) -> Result<usize, GitmapError> {
+        let names = self.iter_tree(self.current_tree_id(), prefix).map(|entry| entry.map(|entry| entry.name().to_string())).collect::<Result<Vec<_>, _>>()?;
+        if !names.is_empty() {
+            let changes: Vec<(&str, Option<Oid>)> = names.iter().map(|name| (name.as_str(), None)).collect();
+            self.stage_changes(&changes)?;
+        }
+        Ok(names.len())
+    }
+
+    /// Stages moving the value of the `from` key to the `to` key with a single
+    /// tree write. An existing `to` key is overwritten and aliases of `from`
+    /// are left dangling. Errors when `from` does not exist.
+    pub fn rename_key(&mut self, from: &str, to: &str) -> Result<(), GitmapError> {
+        let from = &*self.key_name(from);
+        let to = &*self.key_name(to);
+        check_name(to)?;
+        let blob = match self.repo.find_tree(self.current_tree_id()?)?.get_path(Path::new(from)) {
+            Ok(entry) if entry.kind() == Some(ObjectType::Blob) && !is_meta(from) => entry.id(),
+            _ => return Err(GitmapError::new(ErrorCode::NotFound, ErrorClass::Invalid, format!("key `{}` does not exist", from))),
+        };
+        if from == to {
+            return Ok(());
+        }
+        self.stage_changes(&[(from, None), (to, Some(blob))])
+    }
+
+    /// Returns a unified diff between the committed value of the key and the
+    /// provided value. A missing key diffs as empty and binary content is
+    /// reported as `binary`.
+    pub fn preview_insert_diff(&self, name: &str, new_value: &[u8]) -> Result<String, GitmapError> {
+        let name = &*self.key_name(name);
+        let old_value = match self.has_commits() {
+            true => self.tree_value(self.last_tree_id()?, name).unwrap_or_default(),
+            false => Vec::new(),
+        };
+        let path = Path::new(name);
+        let mut patch = Patch::from_buffers(&old_value, Some(path), new_value, Some(path), None)?;
+        if patch.delta().flags().contains(DiffFlags::BINARY) {
+            return Ok("binary".to_string());
+        }
+        let buf = patch.to_buf()?;
+        Ok(String::from_utf8_lossy(&buf).to_string())
+    }
+
+    /// Returns true if the key content has been changed.
+    pub fn key_changed(&self, name: &str) -> bool {
+        self.try_key_changed(name).unwrap_or(false)
+    }
+
+    /// Returns true if the key content has been changed. Errors when the
+    /// trees can not be compared.
+    pub fn try_key_changed(&self, name: &str) -> Result<bool, GitmapError> {
+        let name = &*self.key_name(name);
+        if !self.has_commits() {
+            return Ok(self.staged_has_key(name));
         }
-        false
-   }
+        let diff = self.staged_diff()?;
+        let changed = diff.deltas().any(|delta| {
+            delta.new_file().path().and_then(|p| p.to_str()) == Some(name)
+        });
+        Ok(changed)
+    }
+
+    /// Diffs the last commit against the staged tree.
+    fn staged_diff(&self) -> Result<Diff<'_>, GitmapError> {
+        let old_tree = self.repo.find_tree(self.last_tree_id()?)?;
+        let new_tree = self.repo.find_tree(self.current_tree_id()?)?;
+        Ok(self.repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?)
+    }
     
-    /// Roll back one commit.
-    // pub fn rollback(&self) -> Result<(), Error> {
-    //     // Hints (I think):
-    //     // Normal repo: git reset --hard <commit-oid>
-    //     // Bare repo: git update-ref refs/heads/master <old-tree-oid>
-    //     Ok(())
-    // }
+    /// List commits of the working branch, newest first.
+    pub fn log(&self) -> Vec<CommitInfo> {
+        self.log_with_limit(usize::MAX)
+    }
+
+    /// Returns properties of the last commit on the working branch. Errors
+    /// when the branch has no commits.
+    pub fn last_commit(&self) -> Result<CommitInfo, GitmapError> {
+        Ok(commit_info(&self.repo.find_commit(self.last_commit_id()?)?))
+    }
+
+    /// List at most `limit` commits of the working branch, newest first.
+    pub fn log_with_limit(&self, limit: usize) -> Vec<CommitInfo> {
+        self.walk_commits(limit, |_| true)
+    }
+
+    /// List commits of the working branch which changed the key, newest
+    /// first. All keys of the root commit count as changed.
+    pub fn log_key(&self, name: &str) -> Vec<CommitInfo> {
+        let name = &*self.key_name(name);
+        self.walk_commits(usize::MAX, |commit| {
+            let diff = match self.commit_diff(commit) {
+                Ok(diff) => diff,
+                Err(_) => return false,
+            };
+            diff.deltas().any(|delta| {
+                delta.new_file().path().and_then(|p| p.to_str()) == Some(name)
+                    || delta.old_file().path().and_then(|p| p.to_str()) == Some(name)
+            })
+        })
+    }
+
+    /// List keys added by the provided commit, excluding modified and removed
+    /// ones. All keys of the root commit count as added.
+    pub fn keys_added_in(&self, commit: Oid) -> Result<Vec<String>, GitmapError> {
+        let commit = self.repo.find_commit(commit)?;
+        let diff = self.commit_diff(&commit)?;
+        Ok(diff.deltas()
+            .filter(|delta| delta.status() == Delta::Added)
+            .filter_map(|delta| delta.new_file().path().and_then(|p| p.to_str()).map(String::from))
+            .filter(|path| !is_meta(path))
+            .collect())
+    }
+
+    /// List values the key had on the working branch as `(commit, time,
+    /// value)`, newest first. A `None` value marks a commit which removed the
+    /// key. Aliases are not followed.
+    pub fn key_history(&self, name: &str) -> Result<KeyHistory, GitmapError> {
+        let name = &*self.key_name(name);
+        let mut history = Vec::new();
+        if !self.has_commits() || is_meta(name) {
+            return Ok(history);
+        }
+        let mut walk = self.repo.revwalk()?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+        walk.push(self.last_commit_id()?)?;
+        for id in walk {
+            let commit = self.repo.find_commit(id?)?;
+            let blob = blob_id(&commit.tree()?, name);
+            let old = match commit.parent(0) {
+                Ok(parent) => blob_id(&parent.tree()?, name),
+                Err(_) => None,
+            };
+            if blob == old {
+                continue;
+            }
+            let value = match blob {
+                Some(id) => Some(self.repo.find_blob(id)?.content().to_vec()),
+                None => None,
+            };
+            history.push((commit.id(), commit.time().seconds(), value));
+        }
+        Ok(history)
+    }
+
+    /// Returns size and history of the key. Returns `None` when the key is
+    /// missing. Aliases are followed.
+    pub fn key_meta(&self, name: &str) -> Result<Option<KeyMeta>, GitmapError> {
+        let tree_id = self.read_tree_id()?;
+        let name = match self.resolve_alias(tree_id, &self.key_name(name))? {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        Ok(self.collect_meta(tree_id, vec![name])?.pop().map(|(_, meta)| meta))
+    }
+
+    /// Returns size and history of all keys in one pass over the history of
+    /// the working branch, sorted by key.
+    pub fn keys_meta(&self) -> Result<Vec<(String, KeyMeta)>, GitmapError> {
+        let tree_id = match self.tree_id.is_some() || self.has_commits() {
+            true => self.read_tree_id()?,
+            false => return Ok(Vec::new()),
+        };
+        let mut names = self.tree_keys(tree_id)?;
+        names.sort();
+        self.collect_meta(tree_id, names)
+    }
+
+    /// Walks the working branch once collecting metadata of the keys present
+    /// in the provided tree.
+    fn collect_meta(&self, tree_id: Oid, names: Vec<String>) -> Result<Vec<(String, KeyMeta)>, GitmapError> {
+        let tree = self.repo.find_tree(tree_id)?;
+        let mut metas: BTreeMap<String, (Oid, KeyMeta)> = BTreeMap::new();
+        for name in names {
+            if let Some(blob) = blob_id(&tree, &name) {
+                let size = self.repo.find_blob(blob)?.size();
+                metas.insert(name, (blob, KeyMeta { size, modified: None, modified_time: None, created: None }));
+            }
+        }
+        if self.has_commits() && !metas.is_empty() {
+            let committed = self.repo.find_tree(self.last_tree_id()?)?;
+            let mut pending: HashSet<String> = metas.iter()
+                .filter(|(name, (blob, _))| blob_id(&committed, name) == Some(*blob))
+                .map(|(name, _)| name.clone())
+                .collect();
+            let mut walk = self.repo.revwalk()?;
+            walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+            walk.push(self.last_commit_id()?)?;
+            for id in walk {
+                let commit = self.repo.find_commit(id?)?;
+                for delta in self.commit_diff(&commit)?.deltas() {
+                    let path = match delta.new_file().path().or(delta.old_file().path()).and_then(|p| p.to_str()) {
+                        Some(path) => path,
+                        None => continue,
+                    };
+                    let meta = match metas.get_mut(path) {
+                        Some((_, meta)) => meta,
+                        None => continue,
+                    };
+                    if pending.remove(path) {
+                        meta.modified = Some(commit.id());
+                        meta.modified_time = Some(commit.time().seconds());
+                    }
+                    if delta.status() == Delta::Added {
+                        meta.created = Some(commit.id());
+                    }
+                }
+            }
+        }
+        Ok(metas.into_iter().map(|(name, (_, meta))| (name, meta)).collect())
+    }
+
+    /// Compares keys of two revisions, which can be anything `git rev-parse`
+    /// understands. Errors when either revision does not exist.
+    pub fn diff_keys(&self, a: &str, b: &str) -> Result<KeyDiff, GitmapError> {
+        let old_tree = self.repo.find_tree(self.rev_tree_id(a)?)?;
+        let new_tree = self.repo.find_tree(self.rev_tree_id(b)?)?;
+        let mut diff = KeyDiff::default();
+        for delta in self.repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?.deltas() {
+            let (list, file) = match delta.status() {
+                Delta::Added => (&mut diff.added, delta.new_file()),
+                Delta::Deleted => (&mut diff.removed, delta.old_file()),
+                _ => (&mut diff.modified, delta.new_file()),
+            };
+            match file.path().and_then(|p| p.to_str()) {
+                Some(path) if !is_meta(path) => list.push(path.to_string()),
+                _ => {},
+            }
+        }
+        Ok(diff)
+    }
+
+    /// List keys removed by the provided commit. The root commit removes
+    /// nothing.
+    pub fn keys_removed_in(&self, commit: Oid) -> Result<Vec<String>, GitmapError> {
+        let commit = self.repo.find_commit(commit)?;
+        let diff = self.commit_diff(&commit)?;
+        Ok(diff.deltas()
+            .filter(|delta| delta.status() == Delta::Deleted)
+            .filter_map(|delta| delta.old_file().path().and_then(|p| p.to_str()).map(String::from))
+            .filter(|path| !is_meta(path))
+            .collect())
+    }
+
+    /// Points the reference back to the commit recorded in its reflog entry
+    /// at `index` (0 is the latest) and returns the commit ID. This recovers
+    /// from accidental history moves like `rollback`.
+    pub fn restore_from_reflog(&mut self, refname: &str, index: usize) -> Result<Oid, GitmapError> {
+        let id = match self.repo.reflog(refname)?.get(index) {
+            Some(entry) => entry.id_new(),
+            None => return Err(GitmapError::new(ErrorCode::NotFound, ErrorClass::Reference, format!("reflog of `{}` has no entry {}", refname, index))),
+        };
+        let message = format!("restore from reflog entry {}", index);
+        let name = match self.repo.find_reference(refname).and_then(|r| r.resolve()) {
+            Ok(mut reference) => reference.set_target(id, &message)?.name().map(String::from),
+            Err(_) => self.repo.reference(refname, id, true, &message)?.name().map(String::from),
+        };
+        let head = self.repo.head().ok().and_then(|h| h.name().map(String::from));
+        if name == head {
+            self.set_staged_tree(None);
+        }
+        Ok(id)
+    }
+
+    /// Returns the number of commits between HEAD and the provided commit or
+    /// `None` when the commit is not reachable from HEAD.
+    pub fn distance_from_head(&self, commit: Oid) -> Result<Option<usize>, GitmapError> {
+        if !self.has_commits() {
+            return Ok(None);
+        }
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back((self.last_commit_id()?, 0));
+        while let Some((id, distance)) = queue.pop_front() {
+            if id == commit {
+                return Ok(Some(distance));
+            }
+            if seen.insert(id) {
+                for parent in self.repo.find_commit(id)?.parent_ids() {
+                    queue.push_back((parent, distance + 1));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Rolls back the last commit on the working branch. The root commit can
+    /// not be rolled back and returns an error.
+    pub fn rollback(&mut self) -> Result<(), GitmapError> {
+        self.rollback_by(1)
+    }
+
+    /// Rolls back the provided number of commits on the working branch by
+    /// moving the branch to an older commit. Staged changes are discarded.
+    /// Errors when the history is not deep enough.
+    pub fn rollback_by(&mut self, count: usize) -> Result<(), GitmapError> {
+        let mut commit = self.repo.find_commit(self.last_commit_id()?)?;
+        for _ in 0..count {
+            commit = match commit.parent(0) {
+                Ok(parent) => parent,
+                Err(_) => return Err(GitmapError::new(ErrorCode::NotFound, ErrorClass::Reference, format!("history is too short to roll back {} commit(s)", count))),
+            };
+        }
+        let id = commit.id();
+        drop(commit);
+        self.repo.head()?.set_target(id, "rollback")?;
+        self.set_staged_tree(None);
+        Ok(())
+    }
+
+    /// Walks commits of the working branch, newest first, and collects up to
+    /// `limit` commits accepted by the `filter`.
+    fn walk_commits<F: Fn(&Commit) -> bool>(&self, limit: usize, filter: F) -> Vec<CommitInfo> {
+        let mut infos = Vec::new();
+        if !self.has_commits() || limit == 0 {
+            return infos;
+        }
+        let mut walk = match self.repo.revwalk() {
+            Ok(walk) => walk,
+            Err(_) => return infos,
+        };
+        if walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME).is_err() {
+            return infos;
+        }
+        match self.last_commit_id() {
+            Ok(id) => if walk.push(id).is_err() {
+                return infos;
+            },
+            Err(_) => return infos,
+        }
+        for id in walk {
+            let commit = match id.and_then(|id| self.repo.find_commit(id)) {
+                Ok(commit) => commit,
+                Err(_) => break,
+            };
+            if filter(&commit) {
+                infos.push(commit_info(&commit));
+                if infos.len() == limit {
+                    break;
+                }
+            }
+        }
+        infos
+    }
+
+    /// Diffs the commit against its first parent. The root commit is diffed
+    /// against nothing so all its keys are added.
+    fn commit_diff(&self, commit: &Commit) -> Result<Diff<'_>, GitmapError> {
+        let old_tree = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            Err(_) => None,
+        };
+        Ok(self.repo.diff_tree_to_tree(old_tree.as_ref(), Some(&commit.tree()?), None)?)
+    }
+
+    /// Retrieves key content from the provided tree following aliases.
+    fn tree_value(&self, tree_id: Oid, name: &str) -> Option<Vec<u8>> {
+        self.try_tree_value(tree_id, name).unwrap_or(None)
+    }
+
+    /// Retrieves key content from the provided tree following aliases and
+    /// propagating errors.
+    fn try_tree_value(&self, tree_id: Oid, name: &str) -> Result<Option<Vec<u8>>, GitmapError> {
+        match self.resolve_alias(tree_id, name)? {
+            Some(name) => self.try_tree_key(tree_id, &name),
+            None => Ok(None),
+        }
+    }
+
+    /// Follows aliases in the provided tree and returns the name of the real
+    /// key or `None` when the name is missing. Errors when aliases loop.
+    fn resolve_alias(&self, tree_id: Oid, name: &str) -> Result<Option<String>, GitmapError> {
+        let aliases = match self.try_tree_key(tree_id, ALIASES_META)? {
+            Some(data) => decode_aliases(&data),
+            None => Vec::new(),
+        };
+        self.follow_aliases(tree_id, &aliases, name)
+    }
+
+    /// Follows the provided aliases in the provided tree.
+    fn follow_aliases(&self, tree_id: Oid, aliases: &[(String, String)], name: &str) -> Result<Option<String>, GitmapError> {
+        if is_meta(name) {
+            return Ok(None);
+        }
+        let tree = self.repo.find_tree(tree_id)?;
+        let mut name = name.to_string();
+        for _ in 0..MAX_ALIAS_DEPTH {
+            if has_blob(&tree, &name) {
+                return Ok(Some(name));
+            }
+            name = match aliases.iter().find(|(alias, _)| *alias == name) {
+                Some((_, target)) => target.clone(),
+                None => return Ok(None),
+            };
+        }
+        Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, format!("alias `{}` loops", name)))
+    }
+
+    /// Returns the state of all rotating key prefixes.
+    fn rings(&self) -> Result<Vec<Ring>, GitmapError> {
+        match self.meta(ROTATING_META) {
+            Some(data) => decode_rings(&data),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Reads a metadata entry from the current tree.
+    fn meta(&self, name: &str) -> Option<Vec<u8>> {
+        match self.current_tree_id() {
+            Ok(id) => self.tree_key(id, name),
+            Err(_) => None,
+        }
+    }
+
+    /// Stages a metadata entry. An empty value removes the entry.
+    fn set_meta(&mut self, name: &str, value: &[u8]) -> Result<(), GitmapError> {
+        let blob = match value.is_empty() {
+            true => None,
+            false => Some(self.repo.blob(value)?),
+        };
+        self.stage_changes(&[(name, blob)])
+    }
+
+    /// Applies blob changes to the current tree and stages the result.
+    fn stage_changes(&mut self, changes: &[(&str, Option<Oid>)]) -> Result<(), GitmapError> {
+        let tree_id = self.update_tree(Some(self.current_tree_id()?), changes)?;
+        self.set_staged_tree(Some(tree_id));
+        let mut keys = self.staged_keys.borrow_mut();
+        keys.extend(changes.iter().filter(|(_, blob)| blob.is_some()).map(|(name, _)| name.to_string()));
+        Ok(())
+    }
+
+    /// Replaces the staged tree. The commit the first staged change is based
+    /// on is remembered so commits can detect that the branch moved meanwhile.
+    fn set_staged_tree(&mut self, tree_id: Option<Oid>) {
+        if tree_id.is_none() {
+            self.staged_base.set(None);
+            self.staged_keys.borrow_mut().clear();
+        } else if self.tree_id.is_none() {
+            self.staged_base.set(self.branch_tip_id().ok().flatten());
+        }
+        self.tree_id = tree_id;
+    }
+
+    /// Applies blob changes, where `None` removes the key, to the provided
+    /// tree and returns the new tree ID. Nested trees are created as needed
+    /// and removed once empty.
+    fn update_tree(&self, tree_id: Option<Oid>, changes: &[(&str, Option<Oid>)]) -> Result<Oid, GitmapError> {
+        let tree = match tree_id {
+            Some(id) => Some(self.repo.find_tree(id)?),
+            None => None,
+        };
+        Ok(self.tree_builder(tree.as_ref(), "", changes)?.write()?)
+    }
+
+    /// Returns a builder of the tree at the `dir` path with the changes, which
+    /// are relative to `dir`, applied.
+    fn tree_builder(&self, tree: Option<&Tree>, dir: &str, changes: &[(&str, Option<Oid>)]) -> Result<TreeBuilder<'_>, GitmapError> {
+        let mut builder = self.repo.treebuilder(tree)?;
+        let mut nested: BTreeMap<&str, Vec<(&str, Option<Oid>)>> = BTreeMap::new();
+        for (path, blob) in changes {
+            if let Some((name, rest)) = path.split_once('/') {
+                nested.entry(name).or_default().push((rest, *blob));
+                continue;
+            }
+            let kind = builder.get(path)?.map(|entry| entry.kind());
+            match kind {
+                None | Some(Some(ObjectType::Blob)) | Some(Some(ObjectType::Tree)) => {},
+                Some(kind) => return Err(GitmapError::ForeignEntry { key: format!("{}{}", dir, path), kind: kind.unwrap_or(ObjectType::Any) }),
+            }
+            let is_tree = kind == Some(Some(ObjectType::Tree));
+            match blob {
+                Some(_) if is_tree => return Err(GitmapError::new(ErrorCode::Exists, ErrorClass::Tree, format!("key `{}{}` is a directory", dir, path))),
+                Some(blob) => builder.insert(path, *blob, 0o100644).map(|_| ())?,
+                None if !is_tree && builder.get(path)?.is_some() => builder.remove(path)?,
+                None => {},
+            }
+        }
+        for (name, changes) in nested {
+            let subtree = match builder.get(name)? {
+                Some(entry) if entry.kind() == Some(ObjectType::Tree) => Some(self.repo.find_tree(entry.id())?),
+                Some(entry) if entry.kind() != Some(ObjectType::Blob) => return Err(GitmapError::ForeignEntry { key: format!("{}{}", dir, name), kind: entry.kind().unwrap_or(ObjectType::Any) }),
+                Some(_) => return Err(GitmapError::new(ErrorCode::Exists, ErrorClass::Tree, format!("key `{}{}` is not a directory", dir, name))),
+                None => None,
+            };
+            let sub = self.tree_builder(subtree.as_ref(), &format!("{}{}/", dir, name), &changes)?;
+            if !sub.is_empty() {
+                builder.insert(name, sub.write()?, 0o040000)?;
+            } else if subtree.is_some() {
+                builder.remove(name)?;
+            }
+        }
+        Ok(builder)
+    }
+
+    /// Retrieves key content from the provided tree.
+    fn tree_key(&self, tree_id: Oid, name: &str) -> Option<Vec<u8>> {
+        self.try_tree_key(tree_id, name).unwrap_or(None)
+    }
+
+    /// Retrieves key content from the provided tree propagating errors other
+    /// than a missing key.
+    fn try_tree_key(&self, tree_id: Oid, name: &str) -> Result<Option<Vec<u8>>, GitmapError> {
+        let tree = self.repo.find_tree(tree_id)?;
+        let entry = match tree.get_path(Path::new(name)) {
+            Ok(entry) => entry,
+            Err(err) if err.code() == ErrorCode::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        match entry.to_object(&self.repo)?.as_blob() {
+            Some(blob) => Ok(Some(blob.content().to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns whether the name is cached as missing from the tree, and also
+    /// as not resolving as an alias, or `None` when it is not cached.
+    fn cached_miss(&self, tree_id: Oid, name: &str) -> Option<bool> {
+        let cache = self.negative_cache.as_ref()?.borrow();
+        match cache.0 == tree_id {
+            true => cache.1.get(name).copied(),
+            false => None,
+        }
+    }
+
+    /// Remembers the name as missing from the tree when the cache is enabled.
+    fn cache_miss(&self, tree_id: Oid, name: &str, unresolved: bool) {
+        let mut cache = match &self.negative_cache {
+            Some(cache) => cache.borrow_mut(),
+            None => return,
+        };
+        if cache.0 != tree_id || cache.1.len() >= NEGATIVE_CACHE_CAPACITY {
+            *cache = (tree_id, HashMap::new());
+        }
+        *cache.1.entry(name.to_string()).or_insert(unresolved) |= unresolved;
+    }
+
+    /// Errors when the value exceeds the maximum value size.
+    fn check_value(&self, name: &str, value: &[u8]) -> Result<(), GitmapError> {
+        match self.max_value_size {
+            Some(max) if value.len() > max => Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, format!("value of `{}` exceeds the maximum size of {} bytes", name, max))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the value blob and returns its ID. Values already stored are
+    /// only hashed, skipping compression and the write.
+    fn write_blob(&self, value: &[u8]) -> Result<Oid, GitmapError> {
+        let id = Oid::hash_object(ObjectType::Blob, value)?;
+        if self.repo.odb()?.exists(id) {
+            return Ok(id);
+        }
+        Ok(self.repo.blob(value)?)
+    }
+
+    /// Normalizes the key name according to the case sensitivity mode.
+    fn key_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive {
+            Cow::Owned(name.to_lowercase())
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
+
+    /// Returns the signature used for new commits.
+    fn signature(&self) -> Result<Signature<'static>, GitmapError> {
+        let sig = match (&self.deterministic, &self.identity) {
+            (Some((name, email, epoch)), _) => Signature::new(name, email, &Time::new(*epoch, 0)),
+            (None, Some((name, email))) => Signature::now(name, email),
+            (None, None) => self.repo.signature().or_else(|_| Signature::now(DEFAULT_IDENTITY.0, DEFAULT_IDENTITY.1)),
+        };
+        Ok(sig?)
+    }
+
+    /// Creates an empty tree and returns its ID.
+    fn empty_tree_id(&self) -> Result<Oid, GitmapError> {
+        Ok(self.repo.treebuilder(None)?.write()?)
+    }
+
+    /// Current working tree ID.
+    fn current_tree_id(&self) -> Result<Oid, GitmapError> {
+        self.staged_tree_oid()
+    }
+
+    /// Returns true if the key exists including staged changes, even in
+    /// strict committed reads mode.
+    fn staged_has_key(&self, name: &str) -> bool {
+        self.tree_has_key(self.current_tree_id(), &self.key_name(name))
+    }
+
+    /// Returns true if the key exists in the tree.
+    fn tree_has_key(&self, tree_id: Result<Oid, GitmapError>, name: &str) -> bool {
+        let tree_id = match tree_id {
+            Ok(id) => id,
+            Err(_) => return false,
+        };
+        if self.cached_miss(tree_id, name).is_some() {
+            return false;
+        }
+        let tree = match self.repo.find_tree(tree_id) {
+            Ok(tree) => tree,
+            Err(_) => return false,
+        };
+        let exists = !is_meta(name) && has_blob(&tree, name);
+        if !exists {
+            self.cache_miss(tree_id, name, false);
+        }
+        exists
+    }
+
+    /// Tree ID key reads and listings operate on, which ignores staged changes
+    /// in strict committed reads mode.
+    fn read_tree_id(&self) -> Result<Oid, GitmapError> {
+        match self.strict_committed_reads && self.tree_id.is_some() {
+            true => self.last_tree_id(),
+            false => self.current_tree_id(),
+        }
+    }
+    
+    /// Tree ID of the commit at the provided revision.
+    fn rev_tree_id(&self, rev: &str) -> Result<Oid, GitmapError> {
+        Ok(self.repo.find_commit(self.resolve_rev(rev)?)?.tree_id())
+    }
+
+    /// Tree ID at the tip of the provided branch.
+    fn branch_tree_id(&self, name: &str) -> Result<Oid, GitmapError> {
+        Ok(self.repo.find_branch(name, BranchType::Local)?.get().peel_to_tree()?.id())
+    }
+
+    /// Last commited tree ID.
+    fn last_tree_id(&self) -> Result<Oid, GitmapError> {
+        self.committed_tree_oid()
+    }
+
+    /// Last commit ID. 
+    fn last_commit_id(&self) -> Result<Oid, GitmapError> {
+        self.head_oid()
+    }
+
+    /// Commit ID at the tip of the working branch read from the branch ref
+    /// itself, or of HEAD when detached. Returns `None` when the branch has
+    /// no commits.
+    fn branch_tip_id(&self) -> Result<Option<Oid>, GitmapError> {
+        let head = self.repo.find_reference("HEAD")?;
+        let refname = match head.symbolic_target() {
+            Some(refname) => refname,
+            None => return Ok(head.target()),
+        };
+        match self.repo.find_reference(refname) {
+            Ok(reference) => Ok(Some(reference.peel_to_commit()?.id())),
+            Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Applies the configuration of repositories created by gitmap.
+fn configure(repo: &Repository) -> Result<(), GitmapError> {
+    let mut config = repo.config()?;
+    config.set_bool("core.logallrefupdates", true)?;
+    config.set_bool("core.autocrlf", false)?;
+    config.set_bool("core.ignorecase", false)?;
+    config.set_bool("core.precomposeunicode", false)?;
+    Ok(())
+}
+
+/// Collects commit properties.
+fn commit_info(commit: &Commit) -> CommitInfo {
+    let author = commit.author();
+    CommitInfo {
+        oid: commit.id(),
+        author_name: String::from_utf8_lossy(author.name_bytes()).to_string(),
+        author_email: String::from_utf8_lossy(author.email_bytes()).to_string(),
+        timestamp: commit.time().seconds(),
+        message: String::from_utf8_lossy(commit.message_bytes()).to_string(),
+    }
+}
+
+/// Returns true if the name is reserved for gitmap metadata.
+fn is_meta(name: &str) -> bool {
+    name.starts_with(META_PREFIX)
+}
+
+/// Returns true if the delta changes a metadata entry.
+fn is_meta_delta(delta: &DiffDelta) -> bool {
+    delta.old_file().path().or(delta.new_file().path()).and_then(|p| p.to_str()).is_some_and(is_meta)
+}
+
+/// Returns the ID of the blob the path points at in the tree.
+fn blob_id(tree: &Tree, path: &str) -> Option<Oid> {
+    match tree.get_path(Path::new(path)) {
+        Ok(entry) if entry.kind() == Some(ObjectType::Blob) => Some(entry.id()),
+        _ => None,
+    }
+}
+
+/// Returns true if the path points at a blob of the tree.
+fn has_blob(tree: &Tree, path: &str) -> bool {
+    match tree.get_path(Path::new(path)) {
+        Ok(entry) => entry.kind() == Some(ObjectType::Blob),
+        Err(_) => false,
+    }
+}
+
+/// Returns the object type of a foreign entry at the path of the tree, which
+/// is neither a value nor a directory.
+fn foreign_kind(tree: &Tree, path: &str) -> Option<ObjectType> {
+    match tree.get_path(Path::new(path)).ok()?.kind() {
+        Some(ObjectType::Blob) | Some(ObjectType::Tree) => None,
+        kind => Some(kind.unwrap_or(ObjectType::Any)),
+    }
+}
+
+/// Converts an I/O error of a value source.
+fn io_error(err: io::Error) -> GitmapError {
+    GitmapError::new(ErrorCode::GenericError, ErrorClass::Os, err.to_string())
+}
+
+/// Errors when the name can not be used as a key.
+fn check_name(name: &str) -> Result<(), GitmapError> {
+    if name.split('/').any(is_meta) {
+        return Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, format!("key names starting with `{}` are reserved", META_PREFIX)));
+    }
+    if name.split('/').any(str::is_empty) {
+        return Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, format!("key name `{}` has an empty path segment", name)));
+    }
+    Ok(())
+}
+
+/// Matches the name against a glob pattern where `?` matches any single
+/// character and `*` any sequence of characters.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            },
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match star {
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Serializes metadata fields as NUL terminated strings. NUL can not appear
+/// in tree entry names so it's a safe separator.
+fn encode_fields<S: AsRef<str>>(fields: &[S]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for field in fields {
+        data.extend_from_slice(field.as_ref().as_bytes());
+        data.push(0);
+    }
+    data
+}
+
+/// Parses metadata fields serialized with `encode_fields`.
+fn decode_fields(data: &[u8]) -> Vec<String> {
+    let mut fields: Vec<String> = data.split(|b| *b == 0).map(|p| String::from_utf8_lossy(p).to_string()).collect();
+    fields.pop(); // trailing terminator
+    fields
+}
+
+/// Serializes aliases as `alias` and `target` field pairs.
+fn encode_aliases(aliases: &[(String, String)]) -> Vec<u8> {
+    let fields: Vec<&str> = aliases.iter().flat_map(|(alias, target)| vec![alias.as_str(), target.as_str()]).collect();
+    encode_fields(&fields)
+}
+
+/// Parses aliases serialized with `encode_aliases`.
+fn decode_aliases(data: &[u8]) -> Vec<(String, String)> {
+    decode_fields(data).chunks_exact(2).map(|p| (p[0].clone(), p[1].clone())).collect()
+}
+
+/// Serializes rotating key state as `prefix`, `capacity`, `next` and `count`
+/// field groups.
+fn encode_rings(rings: &[Ring]) -> Vec<u8> {
+    let fields: Vec<String> = rings.iter().flat_map(|ring| vec![
+        ring.prefix.clone(), ring.capacity.to_string(), ring.next.to_string(), ring.count.to_string(),
+    ]).collect();
+    encode_fields(&fields)
+}
+
+/// Parses rotating key state serialized with `encode_rings`. Errors when a
+/// row is incomplete, a number does not parse or the state is impossible,
+/// like a zero capacity.
+fn decode_rings(data: &[u8]) -> Result<Vec<Ring>, GitmapError> {
+    let fields = decode_fields(data);
+    let corrupt = || GitmapError::new(ErrorCode::Invalid, ErrorClass::Invalid, format!("`{}` is corrupt", ROTATING_META));
+    if !fields.len().is_multiple_of(4) {
+        return Err(corrupt());
+    }
+    fields.chunks_exact(4).map(|p| {
+        let number = |field: &str| field.parse::<usize>().map_err(|_| corrupt());
+        let ring = Ring { prefix: p[0].clone(), capacity: number(&p[1])?, next: number(&p[2])?, count: number(&p[3])? };
+        match ring.capacity > 0 && ring.next < ring.capacity && ring.count <= ring.capacity {
+            true => Ok(ring),
+            false => Err(corrupt()),
+        }
+    }).collect()
+}
+
+/// Runs `f` on a helper thread and returns its result unless the `timeout`
+/// passes first, in which case a `TimedOut` error is returned.
+fn with_timeout<T, F>(timeout: Duration, f: F) -> Result<T, GitmapError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, GitmapError> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(f());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res,
+        Err(RecvTimeoutError::Timeout) => Err(GitmapError::TimedOut(timeout)),
+        Err(RecvTimeoutError::Disconnected) => Err(GitmapError::new(ErrorCode::GenericError, ErrorClass::Thread, "helper thread panicked")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn initializes_repository() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.path().join("config").exists(), true);
+    }
+
+    #[test]
+    fn initializes_repository_config() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let repo = Repo::init(&path).unwrap();
+        let config = repo.repo.config().unwrap();
+        assert_eq!(config.get_bool("core.logallrefupdates").unwrap(), true);
+        assert_eq!(config.get_bool("core.autocrlf").unwrap(), false);
+        assert_eq!(config.get_bool("core.ignorecase").unwrap(), false);
+        assert_eq!(config.get_bool("core.precomposeunicode").unwrap(), false);
+    }
+
+    #[test]
+    fn opens_repository() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        Repo::init(&path).unwrap();
+        let repo = Repo::open(&path).unwrap();
+        assert_eq!(repo.path().join("config").exists(), true);
+    }
+
+    #[test]
+    fn supports_worktree_repository() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init_worktree(&path).unwrap();
+        assert_eq!(repo.path(), path.join(".git").as_path());
+        repo.insert_key("foo", "bar".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(path.join("foo").exists(), false);
+        let repo = Repo::open_worktree(&path).unwrap();
+        assert_eq!(repo.key("foo"), Some("bar".as_bytes().to_vec()));
+        assert_eq!(Repo::open_worktree(TempDir::new().unwrap().path()).is_err(), true);
+    }
+
+    #[test]
+    fn discovers_repository() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        Repo::init(&path).unwrap();
+        let repo = Repo::discover(path.join("refs").join("heads")).unwrap();
+        assert_eq!(repo.path().join("config").exists(), true);
+        assert_eq!(Repo::discover(TempDir::new().unwrap().path()).is_err(), true);
+    }
+
+    #[test]
+    fn opens_repository_with_timeout() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        Repo::init(&path).unwrap();
+        let repo = Repo::open_with_timeout(&path, Duration::from_secs(5)).unwrap();
+        assert_eq!(repo.path().join("config").exists(), true);
+        let res = with_timeout(Duration::from_millis(10), || {
+            thread::sleep(Duration::from_secs(1));
+            Ok(())
+        });
+        assert_eq!(matches!(res, Err(GitmapError::TimedOut(timeout)) if timeout == Duration::from_millis(10)), true);
+    }
+
+    #[test]
+    fn commits_with_timeout() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit_with_timeout("", Duration::from_secs(5)).unwrap();
+        assert_eq!(repo.has_commits(), true);
+        assert_eq!(String::from_utf8(repo.key("foo").unwrap()).unwrap(), "1");
+    }
+
+    #[test]
+    fn reads_and_writes_single_keys() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(Repo::read_key(&path, None, "foo").unwrap(), None);
+        Repo::write_key(&path, None, "foo", "1".as_bytes(), "").unwrap();
+        assert_eq!(Repo::read_key(&path, None, "foo").unwrap().unwrap(), "1".as_bytes());
+        repo.switch_branch("staging").unwrap();
+        repo.switch_branch("master").unwrap();
+        Repo::write_key(&path, Some("staging"), "foo", "2".as_bytes(), "").unwrap();
+        Repo::write_key(&path, Some("staging"), "bar", "3".as_bytes(), "").unwrap();
+        assert_eq!(Repo::read_key(&path, Some("staging"), "foo").unwrap().unwrap(), "2".as_bytes());
+        assert_eq!(Repo::read_key(&path, Some("staging"), "bar").unwrap().unwrap(), "3".as_bytes());
+        assert_eq!(Repo::read_key(&path, None, "foo").unwrap().unwrap(), "1".as_bytes());
+        assert_eq!(Repo::read_key(&path, None, "bar").unwrap(), None);
+        assert_eq!(Repo::read_key(&path, Some("missing"), "foo").is_err(), true);
+        assert_eq!(Repo::write_key(&path, Some("missing"), "foo", "".as_bytes(), "").is_err(), true);
+        assert_eq!(repo.branch().unwrap(), "master");
+    }
+
+    #[test]
+    fn checks_commits_existance() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.has_commits(), false);
+        repo.commit("").unwrap(); // initial commit
+        assert_eq!(repo.has_commits(), true);
+    }
+
+    #[test]
+    fn checks_branches_existance() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.has_branches(), false);
+        repo.commit("").unwrap(); // initial commit
+        assert_eq!(repo.has_branches(), true);
+    }
+
+    #[test]
+    fn checks_keys_existance() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.has_keys(), false);
+        repo.insert_key("bar", "".as_bytes()).unwrap();
+        repo.commit("").unwrap(); // initial commit
+        assert_eq!(repo.has_keys(), true);
+    }
+
+    #[test]
+    fn checks_branch_existance() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.has_branch("master"), false); // empty repository
+        repo.commit("").unwrap(); // initial commit
+        assert_eq!(repo.has_branch("master"), true);
+        repo.switch_branch("foo").unwrap();
+        repo.switch_branch("bar").unwrap();
+        assert_eq!(repo.has_branch("foo"), true);
+    }
+
+    #[test]
+    fn checks_key_existance() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.has_key("bar"), false);
+        repo.insert_key("foo", "111".as_bytes()).unwrap();
+        repo.insert_key("bar", "222".as_bytes()).unwrap();
+        repo.insert_key("baz", "333".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.has_key("bar"), true);
+    }
+
+    #[test]
+    fn provides_branches() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.branches().len(), 0);
+        repo.commit("").unwrap(); // initial commit
+        assert_eq!(repo.branches(), ["master"]);
+    }
+
+    #[test]
+    fn counts_keys() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.len(), 0);
+        assert_eq!(repo.is_empty(), true);
+        assert_eq!(repo.len_committed(), 0);
+        repo.insert_key("foo", "".as_bytes()).unwrap();
+        repo.alias_key("bar", "foo").unwrap();
+        assert_eq!(repo.len(), 1);
+        assert_eq!(repo.is_empty(), false);
+        assert_eq!(repo.len_committed(), 0);
+        repo.commit("").unwrap();
+        repo.insert_key("baz", "".as_bytes()).unwrap();
+        repo.insert_key("dir/qux", "".as_bytes()).unwrap();
+        assert_eq!(repo.len(), 3);
+        assert_eq!(repo.len_committed(), 1);
+        repo.remove().unwrap();
+        assert_eq!(repo.len(), 0);
+        assert_eq!(repo.is_empty(), true);
+        assert_eq!(repo.has_keys(), false);
+        assert_eq!(repo.len_committed(), 1);
+        repo.commit("").unwrap();
+        assert_eq!(repo.len_committed(), 0);
+    }
+
+    #[test]
+    fn provides_keys() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.keys().len(), 0);
+        repo.insert_key("foo", "".as_bytes()).unwrap();
+        repo.insert_key("bar", "".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.keys(), ["bar", "foo"]);
+    }
+
+    #[test]
+    fn provides_keys_with_prefix() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.keys_with_prefix("user").len(), 0);
+        repo.insert_key("user:1", "".as_bytes()).unwrap();
+        repo.insert_key("user:12:profile", "".as_bytes()).unwrap();
+        repo.insert_key("session:abc", "".as_bytes()).unwrap();
+        repo.insert_key("users/a/b", "".as_bytes()).unwrap();
+        assert_eq!(repo.keys_with_prefix("user:1"), ["user:1", "user:12:profile"]);
+        assert_eq!(repo.keys_with_prefix("users/"), ["users/a/b"]);
+        assert_eq!(repo.keys_with_prefix("session:abc"), ["session:abc"]);
+        assert_eq!(repo.keys_with_prefix("missing").len(), 0);
+        assert_eq!(repo.keys_matching("user:*:*"), ["user:12:profile"]);
+        assert_eq!(repo.keys_matching("*:?"), ["user:1"]);
+        assert_eq!(repo.keys_matching("*s*/*"), ["users/a/b"]);
+        assert_eq!(repo.keys_matching("session:abc"), ["session:abc"]);
+        assert_eq!(repo.keys_matching("*x*").len(), 0);
+    }
+
+    #[test]
+    fn removes_keys_with_prefix() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("user:1", "".as_bytes()).unwrap();
+        repo.insert_key("users/a", "".as_bytes()).unwrap();
+        repo.insert_key("session:abc", "".as_bytes()).unwrap();
+        repo.alias_key("user:alias", "session:abc").unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.remove_keys_with_prefix("missing").unwrap(), 0);
+        assert_eq!(repo.changed(), false);
+        assert_eq!(repo.remove_keys_with_prefix("user").unwrap(), 2);
+        assert_eq!(repo.keys(), ["session:abc"]);
+        assert_eq!(repo.key("user:alias"), Some(Vec::new()));
+    }
+
+    #[test]
+    fn compares_branch_keys() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "".as_bytes()).unwrap();
+        repo.insert_key("bar", "".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.switch_branch("staging").unwrap();
+        repo.remove_key("foo").unwrap();
+        repo.insert_key("baz", "".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.keys_on_branch("master").unwrap(), ["bar", "foo"]);
+        assert_eq!(repo.keys_intersection("staging", "master").unwrap(), ["bar"]);
+        assert_eq!(repo.keys_union("staging", "master").unwrap(), ["bar", "baz", "foo"]);
+        assert_eq!(repo.keys_difference("staging", "master").unwrap(), ["baz"]);
+        assert_eq!(repo.keys_difference("master", "staging").unwrap(), ["foo"]);
+        assert_eq!(repo.keys_union("staging", "missing").is_err(), true);
+    }
+
+    #[test]
+    fn provides_current_branch() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.branch().is_none(), true);
+        repo.commit("").unwrap(); // initial commit
+        assert_eq!(repo.branch().unwrap(), "master");
+    }
+    
+    #[test]
+    fn provides_key_value() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.key("bar").is_none(), true);
+        repo.insert_key("foo", "111".as_bytes()).unwrap();
+        repo.insert_key("bar", "222".as_bytes()).unwrap();
+        repo.insert_key("baz", "333".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(String::from_utf8(repo.key("bar").unwrap()).unwrap(), "222");
+    }
+
+    #[test]
+    fn inserts_multiple_keys() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        let entries: Vec<(String, Vec<u8>)> = (0..1000).map(|i| (format!("dir{}/key{}", i % 10, i), i.to_string().into_bytes())).collect();
+        for (name, value) in &entries {
+            repo.insert_key(name, value).unwrap();
+        }
+        let expected = repo.tree_id;
+        repo.reset().unwrap();
+        repo.insert_keys(entries).unwrap();
+        assert_eq!(repo.tree_id, expected);
+        assert_eq!(repo.len(), 1000);
+        let failing = vec![("ok".to_string(), Vec::new()), (".gitmap.x".to_string(), Vec::new())];
+        assert_eq!(repo.insert_keys(failing).is_err(), true);
+        assert_eq!(repo.tree_id, expected);
+    }
+
+    #[test]
+    fn reuses_stored_values() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        let value = "x".repeat(100_000);
+        repo.insert_key("foo", value.as_bytes()).unwrap();
+        let blob = repo.write_blob(value.as_bytes()).unwrap();
+        assert_eq!(blob, repo.repo.blob(value.as_bytes()).unwrap());
+        repo.insert_key("bar", value.as_bytes()).unwrap();
+        repo.insert_keys(vec![("baz".to_string(), value.as_bytes().to_vec())]).unwrap();
+        let tree = repo.repo.find_tree(repo.tree_id.unwrap()).unwrap();
+        assert_eq!(["foo", "bar", "baz"].iter().all(|name| blob_id(&tree, name) == Some(blob)), true);
+        assert_eq!(repo.write_blob("new".as_bytes()).unwrap(), Oid::hash_object(ObjectType::Blob, "new".as_bytes()).unwrap());
+        assert_eq!(repo.repo.odb().unwrap().exists(Oid::hash_object(ObjectType::Blob, "new".as_bytes()).unwrap()), true);
+    }
+
+    #[test]
+    fn inserts_key_conditionally() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.insert_key_if("foo", Some("1".as_bytes()), "2".as_bytes()).unwrap(), false);
+        assert_eq!(repo.has_key("foo"), false);
+        assert_eq!(repo.insert_key_if("foo", None, "1".as_bytes()).unwrap(), true);
+        repo.commit("").unwrap();
+        assert_eq!(repo.insert_key_if("foo", None, "2".as_bytes()).unwrap(), false);
+        assert_eq!(repo.insert_key_if("foo", Some("3".as_bytes()), "2".as_bytes()).unwrap(), false);
+        assert_eq!(repo.key("foo").unwrap(), "1".as_bytes());
+        assert_eq!(repo.insert_key_if("foo", Some("1".as_bytes()), "2".as_bytes()).unwrap(), true);
+        assert_eq!(repo.key("foo").unwrap(), "2".as_bytes());
+        assert_eq!(repo.insert_key_if("foo", Some("1".as_bytes()), "3".as_bytes()).unwrap(), false);
+        assert_eq!(repo.insert_key_if("foo", Some("2".as_bytes()), "3".as_bytes()).unwrap(), true);
+    }
+
+    #[test]
+    fn inserts_absent_key() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.insert_key_if_absent("foo", "1".as_bytes()).unwrap(), true);
+        assert_eq!(repo.insert_key_if_absent("foo", "2".as_bytes()).unwrap(), false);
+        repo.commit("").unwrap();
+        assert_eq!(repo.insert_key_if_absent("foo", "2".as_bytes()).unwrap(), false);
+        assert_eq!(repo.key("foo").unwrap(), "1".as_bytes());
+        assert_eq!(repo.changed(), false);
+    }
+
+    #[test]
+    fn compares_and_swaps_current_value() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.compare_and_swap("foo", None, Some("1".as_bytes())).unwrap(), true);
+        assert_eq!(repo.compare_and_swap("foo", None, Some("2".as_bytes())).unwrap(), false);
+        let id = repo.commit("").unwrap();
+        repo.insert_key("foo", "staged".as_bytes()).unwrap();
+        let staged = repo.staged_tree_oid().unwrap();
+        assert_eq!(repo.compare_and_swap("foo", Some("1".as_bytes()), Some("2".as_bytes())).unwrap(), false);
+        assert_eq!(repo.compare_and_swap("foo", None, None).unwrap(), false);
+        assert_eq!(repo.staged_tree_oid().unwrap(), staged);
+        assert_eq!(repo.compare_and_swap("foo", Some("staged".as_bytes()), Some("2".as_bytes())).unwrap(), true);
+        assert_eq!(repo.key("foo").unwrap(), "2".as_bytes());
+        repo.reset().unwrap();
+        assert_eq!(repo.compare_and_swap("foo", Some("1".as_bytes()), None).unwrap(), true);
+        assert_eq!(repo.has_key("foo"), false);
+        assert_eq!(repo.compare_and_swap("bar", None, None).unwrap(), true);
+        repo.commit("").unwrap();
+        assert_eq!(repo.log()[1].oid, id);
+        assert_eq!(repo.keys().len(), 0);
+    }
+
+    #[test]
+    fn limits_value_size() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("big", &[0; 1024]).unwrap();
+        repo.set_max_value_size(4);
+        repo.insert_key("foo", "1234".as_bytes()).unwrap();
+        assert_eq!(repo.insert_key("bar", "12345".as_bytes()).is_err(), true);
+        assert_eq!(repo.insert_keys(vec![("bar".to_string(), vec![0; 5])]).is_err(), true);
+        assert_eq!(repo.insert_rotating("ring.", &[0; 5], 2).is_err(), true);
+        assert_eq!(repo.batch().insert("bar", &[0; 5]).is_err(), true);
+        assert_eq!(repo.keys(), ["big", "foo"]);
+    }
+
+    #[test]
+    fn handles_nested_keys() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("config/app/theme", "dark".as_bytes()).unwrap();
+        repo.insert_key("config/app/font", "mono".as_bytes()).unwrap();
+        repo.insert_key("a/b/c/d/e/f", "deep".as_bytes()).unwrap();
+        repo.insert_key("top", "".as_bytes()).unwrap();
+        assert_eq!(repo.keys(), ["a/b/c/d/e/f", "config/app/font", "config/app/theme", "top"]);
+        assert_eq!(repo.len(), 4);
+        assert_eq!(repo.key("config/app/theme").unwrap(), "dark".as_bytes());
+        assert_eq!(repo.key("a/b/c/d/e/f").unwrap(), "deep".as_bytes());
+        assert_eq!(repo.has_key("config/app/theme"), true);
+        assert_eq!(repo.has_key("config/app"), false);
+        assert_eq!(repo.key("config/app"), None);
+        assert_eq!(repo.keys_under("config"), ["config/app/font", "config/app/theme"]);
+        assert_eq!(repo.keys_under("config/app/"), ["config/app/font", "config/app/theme"]);
+        assert_eq!(repo.keys_under("top"), Vec::<String>::new());
+        assert_eq!(repo.insert_key("top/nested", "".as_bytes()).is_err(), true);
+        assert_eq!(repo.insert_key("config/app", "".as_bytes()).is_err(), true);
+        assert_eq!(repo.insert_key("config//app", "".as_bytes()).is_err(), true);
+        assert_eq!(repo.key("top").unwrap(), "".as_bytes());
+        repo.commit("").unwrap();
+        assert_eq!(repo.key_at("config/app/font", "HEAD").unwrap(), "mono".as_bytes());
+        repo.remove_key("a/b/c/d/e/f").unwrap();
+        assert_eq!(repo.keys_under("a"), Vec::<String>::new());
+        assert_eq!(repo.keys(), ["config/app/font", "config/app/theme", "top"]);
+        let tree_id = repo.current_tree_id().unwrap();
+        assert_eq!(repo.repo.find_tree(tree_id).unwrap().get_name("a").is_none(), true);
+        repo.reset_key("a/b/c/d/e/f").unwrap();
+        assert_eq!(repo.key("a/b/c/d/e/f").unwrap(), "deep".as_bytes());
+        repo.remove().unwrap();
+        assert_eq!(repo.len(), 0);
+    }
+
+    #[test]
+    fn handles_nested_key_edge_cases() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("config/db/host", "localhost".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        let tree = repo.repo.find_tree(repo.last_tree_id().unwrap()).unwrap();
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.get_name("config").unwrap().kind(), Some(ObjectType::Tree));
+        let repo = Repo::open(&path).unwrap();
+        assert_eq!(repo.key("config/db/host").unwrap(), "localhost".as_bytes());
+        assert_eq!(repo.has_key("config/db/host"), true);
+        let mut repo = repo;
+        for name in ["config/db/", "/config", "config//db/port", "", "config/.gitmap.aliases"] {
+            assert_eq!(repo.insert_key(name, "".as_bytes()).is_err(), true);
+        }
+        assert_eq!(repo.insert_key("config/db", "".as_bytes()).is_err(), true);
+        assert_eq!(repo.insert_key("config/db/host/port", "".as_bytes()).is_err(), true);
+        assert_eq!(repo.changed(), false);
+        assert_eq!(repo.key("config/db/"), None);
+        assert_eq!(repo.has_key("config/db/"), false);
+    }
+
+    #[test]
+    fn handles_string_values() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_str("foo", "żółw").unwrap();
+        repo.insert_key("bar", &[0xff, 0xfe]).unwrap();
+        assert_eq!(repo.key_str("foo").unwrap(), "żółw");
+        assert_eq!(repo.key_str("bar"), None);
+        assert_eq!(repo.key("bar").unwrap(), [0xff, 0xfe]);
+        assert_eq!(repo.key_str("missing"), None);
+    }
+
+    #[test]
+    fn handles_key_case() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("Foo", "1".as_bytes()).unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.keys(), ["Foo", "foo"]);
+        assert_eq!(String::from_utf8(repo.key("Foo").unwrap()).unwrap(), "1");
+        assert_eq!(String::from_utf8(repo.key("foo").unwrap()).unwrap(), "2");
+        repo.set_case_insensitive(true);
+        repo.insert_key("BAR", "3".as_bytes()).unwrap();
+        assert_eq!(repo.has_key("bar"), true);
+        assert_eq!(String::from_utf8(repo.key("Bar").unwrap()).unwrap(), "3");
+        assert_eq!(String::from_utf8(repo.key("FOO").unwrap()).unwrap(), "2");
+        repo.remove_key("Bar").unwrap();
+        assert_eq!(repo.keys(), ["Foo", "foo"]);
+    }
+
+    #[test]
+    fn provides_key_value_at_revision() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.key_at("foo", "HEAD").is_none(), true);
+        assert_eq!(repo.keys_at("HEAD").is_err(), true);
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("bar", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        let first = repo.last_commit_id().unwrap().to_string();
+        repo.insert_key("foo", "3".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.remove_key("foo").unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("bar", "4".as_bytes()).unwrap();
+        assert_eq!(String::from_utf8(repo.key_at("foo", &first).unwrap()).unwrap(), "1");
+        assert_eq!(String::from_utf8(repo.key_at("foo", "HEAD~1").unwrap()).unwrap(), "3");
+        assert_eq!(repo.key_at("foo", "HEAD").is_none(), true);
+        assert_eq!(String::from_utf8(repo.key_at("bar", "master").unwrap()).unwrap(), "2");
+        assert_eq!(repo.key_at("foo", "missing").is_none(), true);
+        assert_eq!(repo.keys_at(&first).unwrap(), ["bar", "foo"]);
+        assert_eq!(repo.keys_at("HEAD").unwrap(), ["bar"]);
+        let first = Oid::from_str(&first).unwrap();
+        assert_eq!(repo.key_at_commit("foo", first).unwrap(), "1".as_bytes());
+        assert_eq!(repo.key_at_commit("foo", repo.last_commit_id().unwrap()), None);
+        assert_eq!(repo.key_at_commit("foo", Oid::zero()), None);
+        assert_eq!(String::from_utf8(repo.key("bar").unwrap()).unwrap(), "4"); // staged value intact
+    }
+
+    #[test]
+    fn keeps_foreign_entries() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("dir/bar", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        let gitlink = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let tree_id = {
+            let tree = repo.repo.find_tree(repo.last_tree_id().unwrap()).unwrap();
+            let mut builder = repo.repo.treebuilder(Some(&tree)).unwrap();
+            builder.insert("module", gitlink, 0o160000).unwrap();
+            builder.write().unwrap()
+        };
+        repo.set_staged_tree(Some(tree_id));
+        repo.commit("").unwrap();
+        assert_eq!(repo.keys(), ["dir/bar", "foo"]);
+        assert_eq!(repo.iter().count(), 2);
+        assert_eq!(repo.entries().unwrap().len(), 2);
+        assert_eq!(repo.key("module"), None);
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.remove_key("dir/bar").unwrap();
+        repo.commit("").unwrap();
+        let entry = repo.repo.find_tree(repo.last_tree_id().unwrap()).unwrap().get_name("module").map(|entry| (entry.id(), entry.filemode()));
+        assert_eq!(entry, Some((gitlink, 0o160000)));
+        for err in [repo.insert_key("module", "".as_bytes()), repo.remove_key("module"), repo.reset_key("module"), repo.insert_key("module/foo", "".as_bytes())] {
+            assert_eq!(matches!(err, Err(GitmapError::ForeignEntry { ref key, kind: ObjectType::Commit }) if key == "module"), true);
+        }
+        assert_eq!(repo.changed(), false);
+        repo.set_include_foreign(true);
+        assert_eq!(repo.keys(), ["foo", "module"]);
+        let kinds: Vec<(String, EntryKind)> = repo.iter().map(|entry| entry.map(|entry| (entry.name().to_string(), entry.kind())).unwrap()).collect();
+        assert_eq!(kinds, [("foo".to_string(), EntryKind::Blob), ("module".to_string(), EntryKind::Other)]);
+        let mut foreign = Vec::new();
+        repo.for_each_entry(|name, entry| {
+            if entry.kind == EntryKind::Other {
+                foreign.push((name.to_string(), entry.id));
+            }
+            ControlFlow::Continue(())
+        }).unwrap();
+        assert_eq!(foreign, [("module".to_string(), gitlink)]);
+        assert_eq!(repo.entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reads_large_values_partially() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        let mut seed: u64 = 42;
+        let value: Vec<u8> = (0..3 * 1024 * 1024).map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 56) as u8
+        }).collect();
+        repo.insert_key("large", &value).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("staged", "abc".as_bytes()).unwrap();
+        repo.alias_key("link", "large").unwrap();
+        assert_eq!(repo.key_size("large"), Some(value.len()));
+        assert_eq!(repo.key_size("link"), Some(value.len()));
+        assert_eq!(repo.key_size("staged"), Some(3));
+        assert_eq!(repo.key_size("missing"), None);
+        for (offset, len) in [(0, 16), (1000, 4096), (value.len() - 10, 100), (2 * 1024 * 1024 + 7, 65536)] {
+            assert_eq!(repo.key_range("large", offset, len).unwrap(), &value[offset..(offset + len).min(value.len())]);
+        }
+        assert_eq!(repo.key_range("large", value.len() + 1, 10).unwrap(), Vec::<u8>::new());
+        assert_eq!(repo.key_range("staged", 1, usize::MAX).unwrap(), "bc".as_bytes());
+        assert_eq!(repo.key_range("missing", 0, 1), None);
+        let mut streamed = Vec::new();
+        std::io::copy(&mut repo.key_reader("link").unwrap(), &mut streamed).unwrap();
+        assert_eq!(streamed, value);
+        let mut head = [0; 8];
+        repo.key_reader("large").unwrap().read_exact(&mut head).unwrap();
+        assert_eq!(head, value[..8]);
+        assert_eq!(repo.key_reader("missing").is_none(), true);
+    }
+
+    #[test]
+    fn inserts_streamed_values() {
+        let dir = TempDir::new().unwrap();
+        let mut repo = Repo::init(dir.path().join("repo")).unwrap();
+        let value: Vec<u8> = (0..5 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+        let file = dir.path().join("upload");
+        std::fs::write(&file, &value).unwrap();
+        repo.insert_key_from_file("large", &file).unwrap();
+        assert_eq!(repo.key("large").unwrap() == value, true);
+        let mut streamed = Vec::new();
+        repo.key_reader("large").unwrap().read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed == value, true);
+        repo.insert_key_from("small", "abc".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.key("small").unwrap(), "abc".as_bytes());
+        assert_eq!(repo.insert_key_from_file("missing", dir.path().join("missing")).is_err(), true);
+    }
+
+    #[test]
+    fn discards_failed_streamed_values() {
+        struct Failing(usize);
+        impl Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.checked_sub(buf.len()) {
+                    Some(rest) => {
+                        self.0 = rest;
+                        buf.fill(1);
+                        Ok(buf.len())
+                    },
+                    None => Err(io::Error::other("connection reset")),
+                }
+            }
+        }
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        let tree_id = repo.tree_id;
+        let count_objects = |repo: &Repo| {
+            let mut count = 0;
+            repo.repo.odb().unwrap().foreach(|_| {
+                count += 1;
+                true
+            }).unwrap();
+            count
+        };
+        let objects = count_objects(&repo);
+        let err = repo.insert_key_from("bar", Failing(1024 * 1024)).unwrap_err();
+        assert_eq!(err.message(), "connection reset");
+        assert_eq!(repo.tree_id, tree_id);
+        assert_eq!(count_objects(&repo), objects);
+        repo.set_max_value_size(10);
+        assert_eq!(repo.insert_key_from("bar", "01234567890".as_bytes()).is_err(), true);
+        assert_eq!(repo.insert_key_from(".gitmap.aliases", "".as_bytes()).is_err(), true);
+        assert_eq!(repo.keys(), ["foo"]);
+    }
+
+    #[test]
+    fn reads_key_on_branch() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.switch_branch("staging").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("foo", "3".as_bytes()).unwrap(); // staged only
+        assert_eq!(repo.key_on_branch("master", "foo").unwrap(), "1".as_bytes());
+        assert_eq!(repo.key_on_branch("staging", "foo").unwrap(), "2".as_bytes());
+        assert_eq!(repo.key_on_branch("staging", "bar"), None);
+        assert_eq!(repo.key_on_branch("missing", "foo"), None);
+        assert_eq!(repo.repo.head().unwrap().shorthand(), Some("staging"));
+    }
+
+    #[test]
+    fn reads_committed_data_strictly() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.set_strict_committed_reads(true);
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        assert_eq!(repo.key("foo"), None);
+        assert_eq!(repo.keys().len(), 0);
+        assert_eq!(repo.is_empty(), true);
+        assert_eq!(repo.changed(), true);
+        assert_eq!(repo.key_changed("foo"), true);
+        repo.commit("").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.insert_key("bar", "1".as_bytes()).unwrap();
+        repo.insert_key("baz", "1".as_bytes()).unwrap();
+        repo.remove_key("baz").unwrap();
+        assert_eq!(repo.key("foo"), Some("1".as_bytes().to_vec()));
+        assert_eq!(repo.has_key("bar"), false);
+        assert_eq!(repo.keys(), ["foo"]);
+        assert_eq!(repo.len(), 1);
+        assert_eq!(repo.iter().count(), 1);
+        assert_eq!(repo.entries().unwrap(), [("foo".to_string(), "1".as_bytes().to_vec())]);
+        assert_eq!(repo.staged_key("foo"), Some("2".as_bytes().to_vec()));
+        assert_eq!(repo.staged_changes().unwrap(), [KeyChange::Added("bar".to_string()), KeyChange::Modified("foo".to_string())]);
+        assert_eq!(repo.alias_key("bar", "foo").is_err(), true);
+        repo.commit("").unwrap();
+        assert_eq!(repo.keys(), ["bar", "foo"]);
+        assert_eq!(repo.key("foo"), Some("2".as_bytes().to_vec()));
+        repo.insert_key("qux", "1".as_bytes()).unwrap();
+        repo.set_strict_committed_reads(false);
+        assert_eq!(repo.keys(), ["bar", "foo", "qux"]);
+    }
+
+    #[test]
+    fn caches_missing_keys() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.set_negative_cache(true);
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.alias_key("bar", "foo").unwrap();
+        assert_eq!(repo.key("missing"), None);
+        assert_eq!(repo.key("missing"), None);
+        assert_eq!(repo.has_key("missing"), false);
+        assert_eq!(repo.has_key("bar"), false);
+        assert_eq!(repo.key("bar"), Some("1".as_bytes().to_vec()));
+        assert_eq!(repo.negative_cache.as_ref().unwrap().borrow().1.len(), 2);
+        repo.insert_key("missing", "2".as_bytes()).unwrap();
+        assert_eq!(repo.key("missing"), Some("2".as_bytes().to_vec()));
+        assert_eq!(repo.has_key("missing"), true);
+        assert_eq!(repo.has_key("other"), false);
+        repo.clear_cache();
+        assert_eq!(repo.negative_cache.as_ref().unwrap().borrow().1.len(), 0);
+        repo.set_negative_cache(false);
+        assert_eq!(repo.has_key("other"), false);
+        assert_eq!(repo.negative_cache.is_none(), true);
+    }
+
+    #[test]
+    fn provides_staged_key_value() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.staged_key("foo").is_none(), true);
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.reset().unwrap();
+        assert_eq!(String::from_utf8(repo.staged_key("foo").unwrap()).unwrap(), "1");
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        assert_eq!(String::from_utf8(repo.staged_key("foo").unwrap()).unwrap(), "2");
+        repo.remove_key("foo").unwrap();
+        assert_eq!(repo.staged_key("foo").is_none(), true);
+    }
+
+    #[test]
+    fn aliases_keys() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        assert_eq!(repo.alias_key("bar", "missing").is_err(), true);
+        assert_eq!(repo.alias_key("foo", "foo").is_err(), true); // real key
+        repo.alias_key("bar", "foo").unwrap();
+        repo.alias_key("baz", "bar").unwrap();
+        assert_eq!(repo.alias_key("foo2", "baz").is_ok(), true);
+        repo.commit("").unwrap();
+        assert_eq!(repo.resolve("foo"), Resolved::Key);
+        assert_eq!(repo.resolve("baz"), Resolved::Alias("bar".to_string()));
+        assert_eq!(repo.resolve("qux"), Resolved::Missing);
+        assert_eq!(String::from_utf8(repo.key("baz").unwrap()).unwrap(), "1");
+        assert_eq!(repo.keys(), ["foo"]);
+        assert_eq!(repo.keys_with_aliases(), ["bar", "baz", "foo", "foo2"]);
+        assert_eq!(repo.has_key(ALIASES_META), false);
+        assert_eq!(repo.insert_key(ALIASES_META, "".as_bytes()).is_err(), true);
+        repo.remove_key("foo").unwrap();
+        assert_eq!(repo.dangling_aliases(), ["bar", "baz", "foo2"]);
+        repo.remove_alias("foo2").unwrap();
+        assert_eq!(repo.aliases().len(), 2);
+    }
+
+    #[test]
+    fn rotates_keys() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.rotating_entries("log.").len(), 0);
+        assert_eq!(repo.insert_rotating("log.", "".as_bytes(), 0).is_err(), true);
+        assert_eq!(repo.insert_rotating("log.", "1".as_bytes(), 3).unwrap(), "log.0");
+        assert_eq!(repo.insert_rotating("log.", "2".as_bytes(), 3).unwrap(), "log.1");
+        repo.commit("").unwrap();
+        assert_eq!(repo.rotating_entries("log."), ["log.0", "log.1"]);
+        assert_eq!(repo.insert_rotating("log.", "3".as_bytes(), 3).unwrap(), "log.2");
+        assert_eq!(repo.insert_rotating("log.", "4".as_bytes(), 3).unwrap(), "log.0");
+        assert_eq!(repo.insert_rotating("log.", "5".as_bytes(), 4).is_err(), true);
+        assert_eq!(repo.rotating_entries("log."), ["log.1", "log.2", "log.0"]);
+        assert_eq!(String::from_utf8(repo.key("log.0").unwrap()).unwrap(), "4");
+        assert_eq!(repo.keys(), ["log.0", "log.1", "log.2"]);
+        repo.reset().unwrap();
+        assert_eq!(repo.rotating_entries("log."), ["log.0", "log.1"]);
+    }
+
+    #[test]
+    fn rejects_corrupt_rotating_state() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        for fields in [vec!["log.", "0", "0", "0"], vec!["log.", "x", "0", "0"], vec!["log.", "2", "2", "1"], vec!["log.", "2"]] {
+            repo.set_meta(ROTATING_META, &encode_fields(&fields)).unwrap();
+            assert_eq!(repo.try_rotating_entries("log.").unwrap_err().code(), ErrorCode::Invalid);
+            assert_eq!(repo.rotating_entries("log.").len(), 0);
+            assert_eq!(repo.insert_rotating("log.", "1".as_bytes(), 2).is_err(), true);
+        }
+        repo.set_meta(ROTATING_META, &encode_fields(&["log.", "2", "1", "1"])).unwrap();
+        assert_eq!(repo.try_rotating_entries("log.").unwrap(), ["log.0"]);
+    }
+
+    #[test]
+    fn removes_rotating_state() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_rotating("log.", "1".as_bytes(), 3).unwrap();
+        repo.insert_rotating("log.", "2".as_bytes(), 3).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.alias_key("bar", "foo").unwrap();
+        repo.remove().unwrap();
+        assert_eq!(repo.keys().len(), 0);
+        assert_eq!(repo.rotating_entries("log.").len(), 0);
+        assert_eq!(repo.aliases().len(), 0);
+        assert_eq!(repo.key("bar"), None);
+        assert_eq!(repo.insert_rotating("log.", "3".as_bytes(), 2).unwrap(), "log.0");
+        assert_eq!(repo.rotating_entries("log."), ["log.0"]);
+    }
+
+    #[test]
+    fn detects_alias_loops() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.alias_key("bar", "foo").unwrap();
+        assert_eq!(repo.alias_key("foo", "bar").is_err(), true);
+        repo.set_meta(ALIASES_META, &encode_aliases(&[
+            ("a".to_string(), "b".to_string()),
+            ("b".to_string(), "a".to_string()),
+        ])).unwrap();
+        assert_eq!(repo.key("a").is_none(), true);
+        assert_eq!(repo.resolve_alias(repo.current_tree_id().unwrap(), "a").is_err(), true);
+    }
+
+    #[test]
+    fn switches_branch() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.switch_branch("foo").is_err(), true);
+        repo.commit("").unwrap(); // initial commit
+        assert_eq!(repo.switch_branch("foo").is_ok(), true);
+        assert_eq!(repo.branch().unwrap(), "foo");
+    }
+
+    #[test]
+    fn removes_branch() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.remove_branch("master").is_err(), true); // no branches
+        repo.commit("").unwrap(); // initial commit
+        assert_eq!(repo.remove_branch("master").is_err(), true); // can't remove working branch
+        repo.switch_branch("foo").unwrap();
+        assert_eq!(repo.branches(), ["foo", "master"]);
+        assert_eq!(repo.remove_branch("foo").is_err(), true); // can't remove working branch
+        repo.switch_branch("master").unwrap();
+        assert_eq!(repo.remove_branch("foo").is_ok(), true);
+        assert_eq!(repo.branches(), ["master"]);
+    }
+
+    #[test]
+    fn tags_snapshots() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.tag("release").is_err(), true); // no commits
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.tag("release").unwrap();
+        assert_eq!(repo.tag("release").is_err(), true);
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        let tag = repo.tag_with_message("annotated", "second").unwrap();
+        assert_eq!(repo.repo.find_tag(tag).unwrap().message(), Some("second"));
+        assert_eq!(repo.tag_with_message("annotated", "again").is_err(), true);
+        assert_eq!(repo.tag_with_message("release", "again").is_err(), true);
+        repo.insert_key("foo", "3".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.tags(), ["annotated", "release"]);
+        assert_eq!(repo.has_tag("release"), true);
+        assert_eq!(repo.key_at("foo", "release").unwrap(), "1".as_bytes());
+        assert_eq!(repo.key_at("foo", "annotated").unwrap(), "2".as_bytes());
+        assert_eq!(repo.key("foo").unwrap(), "3".as_bytes());
+        repo.remove_tag("release").unwrap();
+        assert_eq!(repo.has_tag("release"), false);
+        assert_eq!(repo.key_at("foo", "release"), None);
+        assert_eq!(repo.remove_tag("release").is_err(), true);
+    }
+
+    #[test]
+    fn resolves_revisions() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.repo.config().unwrap().set_bool("core.logAllRefUpdates", true).unwrap();
+        let first = repo.commit("").unwrap();
+        repo.tag_with_message("v1", "").unwrap();
+        let second = repo.commit("").unwrap();
+        let third = repo.commit("").unwrap();
+        assert_eq!(repo.resolve_rev("HEAD").unwrap(), third);
+        assert_eq!(repo.resolve_rev("HEAD~2").unwrap(), first);
+        assert_eq!(repo.resolve_rev("master@{1}").unwrap(), second);
+        assert_eq!(repo.resolve_rev("v1").unwrap(), first);
+        assert_eq!(repo.resolve_rev("v1^{commit}").unwrap(), first);
+        assert_eq!(repo.resolve_rev(&second.to_string()[..7]).unwrap(), second);
+        assert_eq!(repo.resolve_rev("HEAD~3").is_err(), true);
+        assert_eq!(repo.resolve_rev("HEAD^{tree}").is_err(), true);
+        assert_eq!(repo.resolve_rev("missing").is_err(), true);
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.resolve_rev("HEAD:foo").is_err(), true);
+    }
+
+    #[test]
+    fn deploys_to_branch() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("staged", "".as_bytes()).unwrap();
+        let id = repo.deploy_to_branch("prod", "deploy", |repo| repo.insert_key("foo", "2".as_bytes())).unwrap();
+        assert_eq!(repo.branch().unwrap(), "master");
+        assert_eq!(repo.keys(), ["foo", "staged"]);
+        assert_eq!(repo.key("foo").unwrap(), "1".as_bytes());
+        assert_eq!(repo.key_at("foo", "prod").unwrap(), "2".as_bytes());
+        assert_eq!(repo.keys_on_branch("prod").unwrap(), ["foo"]);
+        let failed = repo.deploy_to_branch("prod", "deploy", |repo| {
+            repo.insert_key("foo", "3".as_bytes())?;
+            repo.commit("partial")?;
+            Err(GitmapError::from(git2::Error::from_str("failed")))
+        });
+        assert_eq!(failed.is_err(), true);
+        assert_eq!(repo.repo.find_branch("prod", BranchType::Local).unwrap().get().target().unwrap(), id);
+        assert_eq!(repo.deploy_to_branch("temp", "", |repo| repo.insert_key(".gitmap.x", &[])).is_err(), true);
+        assert_eq!(repo.branches(), ["master", "prod"]);
+        assert_eq!(repo.branch().unwrap(), "master");
+        assert_eq!(repo.keys(), ["foo", "staged"]);
+    }
+
+    #[test]
+    fn performs_operations() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.keys(), ["foo"]);
+        repo.insert_key("foo", "11".as_bytes()).unwrap();
+        repo.insert_key("bar", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.keys(), ["bar", "foo"]);
+        repo.remove_key("foo").unwrap();
+        repo.insert_key("bar", "22".as_bytes()).unwrap();
+        repo.reset_key("bar").unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.keys(), ["bar"]);
+        assert_eq!(String::from_utf8(repo.key("bar").unwrap()).unwrap(), "2");
+        repo.remove().unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.keys().len(), 0);
+    }
+
+    #[test]
+    fn resets_keys() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("dir/bar", "1".as_bytes()).unwrap();
+        repo.reset_keys(&["dir/bar"]).unwrap();
+        assert_eq!(repo.keys(), ["foo"]);
+        repo.insert_key("dir/bar", "1".as_bytes()).unwrap();
+        repo.insert_key("baz", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.remove_key("dir/bar").unwrap();
+        repo.insert_key("baz", "2".as_bytes()).unwrap();
+        repo.insert_key("qux", "2".as_bytes()).unwrap();
+        repo.reset_keys(&["foo", "dir/bar", "qux", "missing", ".gitmap.aliases"]).unwrap();
+        assert_eq!(repo.keys(), ["baz", "dir/bar", "foo"]);
+        assert_eq!(repo.key("foo").unwrap(), "1".as_bytes());
+        assert_eq!(repo.key("dir/bar").unwrap(), "1".as_bytes());
+        assert_eq!(repo.key("baz").unwrap(), "2".as_bytes());
+        repo.reset_keys(&["baz"]).unwrap();
+        assert_eq!(repo.changed(), false);
+    }
+
+    #[test]
+    fn renames_key() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.rename_key("foo", "dir/bar").unwrap();
+        assert_eq!(repo.key("foo"), None);
+        assert_eq!(repo.key("dir/bar"), Some("1".as_bytes().to_vec()));
+        repo.commit("").unwrap();
+        assert_eq!(repo.keys(), ["dir/bar"]);
+        repo.rename_key("dir/bar", "dir/bar").unwrap();
+        assert_eq!(repo.changed(), false);
+        assert_eq!(matches!(repo.rename_key("foo", "baz"), Err(GitmapError::NotFound(_))), true);
+        assert_eq!(repo.rename_key("dir", "baz").is_err(), true);
+        assert_eq!(repo.rename_key("dir/bar", ".gitmap.aliases").is_err(), true);
+    }
+
+    #[test]
+    fn restores_keys_from_revision() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("bar", "1".as_bytes()).unwrap();
+        let first = repo.commit("").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.remove_key("bar").unwrap();
+        repo.insert_key("baz", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.restore_key("foo", &first.to_string()).unwrap();
+        repo.restore_key("bar", "HEAD~1").unwrap();
+        assert_eq!(repo.key("foo"), Some("1".as_bytes().to_vec()));
+        assert_eq!(repo.key("bar"), Some("1".as_bytes().to_vec()));
+        assert_eq!(matches!(repo.restore_key("baz", "HEAD~1"), Err(GitmapError::NotFound(_))), true);
+        assert_eq!(repo.restore_key("foo", "HEAD~5").is_err(), true);
+        repo.reset().unwrap();
+        repo.restore_all("HEAD~1").unwrap();
+        repo.commit("revert").unwrap();
+        assert_eq!(repo.keys(), repo.keys_at(&first.to_string()).unwrap());
+        assert_eq!(repo.committed_tree_oid().unwrap(), repo.repo.find_commit(first).unwrap().tree_id());
+        assert_eq!(repo.log().len(), 3);
+        assert_eq!(repo.restore_all("missing").is_err(), true);
+    }
+
+    #[test]
+    fn unstages_key() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.insert_key("bar", "3".as_bytes()).unwrap();
+        repo.unstage_key("foo").unwrap();
+        repo.unstage_key("bar").unwrap();
+        assert_eq!(String::from_utf8(repo.key("foo").unwrap()).unwrap(), "1");
+        assert_eq!(repo.has_key("bar"), false);
+        assert_eq!(repo.changed(), false);
+    }
+
+    #[test]
+    fn provides_log() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.log().len(), 0);
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit_with_author("first", "John", "john@foo.com").unwrap();
+        repo.insert_key("bar", "2".as_bytes()).unwrap();
+        repo.commit("second").unwrap();
+        repo.insert_key("foo", "3".as_bytes()).unwrap();
+        repo.commit("third").unwrap();
+        let log = repo.log();
+        assert_eq!(log.iter().map(|c| c.message.as_str()).collect::<Vec<_>>(), ["third", "second", "first"]);
+        assert_eq!(log[0].id(), repo.last_commit_id().unwrap().to_string());
+        assert_eq!(log[0].oid, repo.last_commit_id().unwrap());
+        assert_eq!(log[2].author_name, "John");
+        assert_eq!(log[2].author_email, "john@foo.com");
+        assert_eq!(repo.log_with_limit(2).len(), 2);
+        assert_eq!(repo.log_key("foo").iter().map(|c| c.message.as_str()).collect::<Vec<_>>(), ["third", "first"]);
+        assert_eq!(repo.log_key("bar").iter().map(|c| c.message.as_str()).collect::<Vec<_>>(), ["second"]);
+        assert_eq!(repo.log_key("baz").len(), 0);
+    }
 
-    /// Creates an empty tree and returns its ID.
-    fn empty_tree_id(&self) -> Result<Oid, Error> {
-        Ok(self.repo.treebuilder(None)?.write()?)
+    #[test]
+    fn provides_keys_added_in_commit() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("bar", "1".as_bytes()).unwrap();
+        repo.alias_key("qux", "foo").unwrap();
+        let root = repo.commit("").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.remove_key("bar").unwrap();
+        repo.insert_key("baz", "1".as_bytes()).unwrap();
+        let second = repo.commit("").unwrap();
+        assert_eq!(repo.keys_added_in(root).unwrap(), ["bar", "foo"]);
+        assert_eq!(repo.keys_added_in(second).unwrap(), ["baz"]);
+        assert_eq!(repo.keys_added_in(Oid::zero()).is_err(), true);
     }
 
-    /// Current working tree ID.
-    fn current_tree_id(&self) -> Result<Oid, Error> {
-        if self.tree_id.is_some() {
-            Ok(self.tree_id.unwrap())
-        } else if !self.has_commits() {
-            self.empty_tree_id()
-        } else {
-            self.last_tree_id()
+    #[test]
+    fn provides_key_history() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.key_history("foo").unwrap().len(), 0);
+        let mut commits = Vec::new();
+        for value in ["1", "2", "3"] {
+            repo.insert_key("foo", value.as_bytes()).unwrap();
+            commits.push(repo.commit(value).unwrap());
         }
+        repo.insert_key("bar", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.remove_key("foo").unwrap();
+        commits.push(repo.commit("removed").unwrap());
+        repo.insert_key("foo", "4".as_bytes()).unwrap();
+        commits.push(repo.commit("restored").unwrap());
+        let history = repo.key_history("foo").unwrap();
+        assert_eq!(history.iter().map(|(id, _, _)| *id).collect::<Vec<_>>(), commits.iter().rev().copied().collect::<Vec<_>>());
+        assert_eq!(history.iter().map(|(_, _, value)| value.as_deref()).collect::<Vec<_>>(), [
+            Some("4".as_bytes()), None, Some("3".as_bytes()), Some("2".as_bytes()), Some("1".as_bytes()),
+        ]);
+        assert_eq!(history[0].1, repo.log()[0].timestamp);
+        assert_eq!(repo.key_history("missing").unwrap().len(), 0);
     }
-    
-    /// Last commited tree ID.
-    fn last_tree_id(&self) -> Result<Oid, Error> {
-        Ok(self.repo.find_commit(self.last_commit_id()?)?.tree_id())
+
+    #[test]
+    fn diffs_keys_between_revisions() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("bar", "1".as_bytes()).unwrap();
+        repo.insert_key("dir/baz", "1".as_bytes()).unwrap();
+        let first = repo.commit("").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.remove_key("bar").unwrap();
+        repo.insert_key("dir/qux", "1".as_bytes()).unwrap();
+        repo.alias_key("alias", "foo").unwrap();
+        repo.commit("").unwrap();
+        let diff = repo.diff_keys(&first.to_string(), "HEAD").unwrap();
+        assert_eq!(diff, KeyDiff { added: vec!["dir/qux".to_string()], removed: vec!["bar".to_string()], modified: vec!["foo".to_string()] });
+        assert_eq!(repo.diff_keys("HEAD~1", "HEAD").unwrap(), diff);
+        assert_eq!(repo.diff_keys("HEAD", "HEAD").unwrap(), KeyDiff::default());
+        assert_eq!(repo.diff_keys("HEAD~5", "HEAD").is_err(), true);
+        assert_eq!(repo.diff_keys("HEAD", "missing").is_err(), true);
     }
 
-    /// Last commit ID. 
-    fn last_commit_id(&self) -> Result<Oid, Error> {
-        Ok(self.repo.revparse_single("HEAD")?.id())
+    #[test]
+    fn provides_keys_removed_in_commit() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("bar", "1".as_bytes()).unwrap();
+        repo.insert_key("dir/baz", "1".as_bytes()).unwrap();
+        repo.alias_key("qux", "foo").unwrap();
+        let root = repo.commit("").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.remove_key("bar").unwrap();
+        repo.remove_key("dir/baz").unwrap();
+        repo.remove_alias("qux").unwrap();
+        let second = repo.commit("").unwrap();
+        assert_eq!(repo.keys_removed_in(root).unwrap().len(), 0);
+        assert_eq!(repo.keys_removed_in(second).unwrap(), ["bar", "dir/baz"]);
+        assert_eq!(repo.keys_removed_in(Oid::zero()).is_err(), true);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use tempfile::TempDir;
-    use super::*;
+    #[test]
+    fn provides_distance_from_head() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.distance_from_head(Oid::zero()).unwrap(), None);
+        repo.commit("").unwrap();
+        let first = repo.last_commit_id().unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.distance_from_head(first).unwrap(), Some(2));
+        assert_eq!(repo.distance_from_head(repo.last_commit_id().unwrap()).unwrap(), Some(0));
+        assert_eq!(repo.distance_from_head(Oid::zero()).unwrap(), None);
+    }
 
     #[test]
-    fn initializes_repository() {
+    fn provides_repository_access() {
         let path = TempDir::new().unwrap().path().to_owned();
-        let repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.path().join("config").exists(), true);
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        let first = repo.commit("").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("bar", "".as_bytes()).unwrap();
+        assert_eq!(repo.with_repository_ref(|r| r.is_bare()), true);
+        assert_eq!(repo.repository().path(), repo.path());
+        repo.with_repository(|r| r.reference("refs/tags/first", first, false, "").map(|_| ())).unwrap();
+        assert_eq!(repo.has_key("bar"), true);
+        repo.with_repository(|r| r.head().unwrap().set_target(first, "").map(|_| ())).unwrap();
+        assert_eq!(repo.has_key("bar"), false);
+        assert_eq!(repo.key("foo").unwrap(), "1".as_bytes());
     }
 
     #[test]
-    fn opens_repository() {
+    fn detects_moved_branch_on_commit() {
         let path = TempDir::new().unwrap().path().to_owned();
-        Repo::init(&path).unwrap();
-        let repo = Repo::open(&path).unwrap();
-        assert_eq!(repo.path().join("config").exists(), true);
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        let id = repo.commit("").unwrap();
+        assert_eq!(repo.log()[0].oid, id);
+        repo.insert_key("bar", "".as_bytes()).unwrap();
+        let external = Repo::write_key(&path, None, "baz", "".as_bytes(), "external").unwrap();
+        assert_eq!(repo.commit("").unwrap_err().code(), ErrorCode::Modified);
+        assert_eq!(repo.last_commit_id().unwrap(), external);
+        repo.reset().unwrap();
+        repo.insert_key("bar", "".as_bytes()).unwrap();
+        let id = repo.commit("").unwrap();
+        assert_eq!(repo.last_commit_id().unwrap(), id);
+        assert_eq!(repo.keys(), ["bar", "baz", "foo"]);
     }
 
     #[test]
-    fn checks_commits_existance() {
+    fn commits_after_switching_branch() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.switch_branch("other").unwrap();
+        repo.insert_key("bar", "1".as_bytes()).unwrap();
+        let other = repo.commit("").unwrap();
+        repo.switch_branch("master").unwrap();
+        repo.insert_key("baz", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.switch_branch("other").unwrap();
+        repo.insert_key("qux", "1".as_bytes()).unwrap();
+        let id = repo.commit("").unwrap();
+        assert_eq!(repo.branch_oid("other").unwrap(), id);
+        assert_eq!(repo.log()[1].oid, other);
+        assert_eq!(repo.key("qux").unwrap(), "1".as_bytes());
+    }
+
+    #[test]
+    fn provides_object_ids() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(matches!(repo.head_oid(), Err(GitmapError::NoCommits)), true);
+        assert_eq!(matches!(repo.committed_tree_oid(), Err(GitmapError::NoCommits)), true);
+        assert_eq!(repo.branch_oid("master").is_err(), true);
+        let empty = repo.staged_tree_oid().unwrap();
+        assert_eq!(repo.repo.find_tree(empty).unwrap().len(), 0);
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        let staged = repo.staged_tree_oid().unwrap();
+        let first = repo.commit("").unwrap();
+        assert_eq!(repo.head_oid().unwrap(), first);
+        assert_eq!(repo.branch_oid("master").unwrap(), first);
+        assert_eq!(repo.committed_tree_oid().unwrap(), staged);
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        let second = repo.commit("").unwrap();
+        repo.repo.set_head_detached(first).unwrap();
+        assert_eq!(repo.head_oid().unwrap(), first);
+        assert_eq!(repo.committed_tree_oid().unwrap(), staged);
+        assert_eq!(repo.branch_oid("master").unwrap(), second);
+    }
+
+    #[test]
+    fn returns_commit_id() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        let first = repo.commit("").unwrap();
+        assert_eq!(first, repo.repo.revparse_single("HEAD").unwrap().id());
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        let second = repo.commit("").unwrap();
+        assert_eq!(second, repo.repo.revparse_single("HEAD").unwrap().id());
+        assert_eq!(repo.repo.find_commit(second).unwrap().parent_id(0).unwrap(), first);
+        let third = Repo::write_key(&path, None, "bar", "2".as_bytes(), "").unwrap();
+        assert_eq!(third, repo.repo.revparse_single("HEAD").unwrap().id());
+    }
+
+    #[test]
+    fn commits_with_author() {
         let path = TempDir::new().unwrap().path().to_owned();
         let repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.has_commits(), false);
-        repo.commit("").unwrap(); // initial commit
-        assert_eq!(repo.has_commits(), true);
+        repo.commit_with_author("", "John", "john@foo.com").unwrap();
+        let commit = repo.repo.find_commit(repo.last_commit_id().unwrap()).unwrap();
+        assert_eq!(commit.author().name().unwrap(), "John");
+        assert_eq!(commit.committer().email().unwrap(), "john@foo.com");
     }
 
     #[test]
-    fn checks_branches_existance() {
+    fn commits_with_offset() {
         let path = TempDir::new().unwrap().path().to_owned();
         let repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.has_branches(), false);
-        repo.commit("").unwrap(); // initial commit
-        assert_eq!(repo.has_branches(), true);
+        repo.commit_with_offset("", -330).unwrap();
+        let commit = repo.repo.find_commit(repo.last_commit_id().unwrap()).unwrap();
+        assert_eq!(commit.time().offset_minutes(), -330);
+        assert_eq!(commit.author().when().offset_minutes(), -330);
+        repo.commit_with_offset("", 0).unwrap();
+        let commit = repo.repo.find_commit(repo.last_commit_id().unwrap()).unwrap();
+        assert_eq!(commit.committer().when().offset_minutes(), 0);
     }
 
     #[test]
-    fn checks_keys_existance() {
+    fn provides_last_commit() {
         let path = TempDir::new().unwrap().path().to_owned();
         let mut repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.has_keys(), false);
-        repo.insert_key("bar", "".as_bytes()).unwrap();
-        repo.commit("").unwrap(); // initial commit
-        assert_eq!(repo.has_keys(), true);
+        assert_eq!(repo.last_commit().is_err(), true);
+        repo.set_signature("John", "john@example.com");
+        repo.commit("first").unwrap();
+        let id = repo.commit("second").unwrap();
+        let commit = repo.last_commit().unwrap();
+        assert_eq!(commit.oid, id);
+        assert_eq!(commit.message, "second");
+        assert_eq!((commit.author_name.as_str(), commit.author_email.as_str()), ("John", "john@example.com"));
+        assert_eq!(commit.timestamp, repo.repo.find_commit(id).unwrap().time().seconds());
     }
 
     #[test]
-    fn checks_branch_existance() {
+    fn commits_detailed() {
         let path = TempDir::new().unwrap().path().to_owned();
         let mut repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.has_branch("master"), false); // empty repository
-        repo.commit("").unwrap(); // initial commit
-        assert_eq!(repo.has_branch("master"), true);
-        repo.switch_branch("foo").unwrap();
-        repo.switch_branch("bar").unwrap();
-        assert_eq!(repo.has_branch("foo"), true);
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("bar", "1".as_bytes()).unwrap();
+        let result = repo.commit_detailed("").unwrap();
+        assert_eq!(result.oid, repo.last_commit_id().unwrap());
+        assert_eq!((result.added, result.modified, result.removed, result.is_initial), (2, 0, 0, true));
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.remove_key("bar").unwrap();
+        repo.insert_key("baz", "1".as_bytes()).unwrap();
+        let result = repo.commit_detailed("").unwrap();
+        assert_eq!((result.added, result.modified, result.removed, result.is_initial), (1, 1, 1, false));
     }
 
     #[test]
-    fn checks_key_existance() {
+    fn commits_with_signature() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        let mut config = repo.repo.config().unwrap().open_level(git2::ConfigLevel::Local).unwrap();
+        config.set_str("user.name", "").unwrap();
+        config.set_str("user.email", "").unwrap();
+        assert_eq!(repo.repo.signature().is_err(), true);
+        repo.commit("default").unwrap();
+        repo.set_signature("John", "john@example.com");
+        repo.commit("custom").unwrap();
+        let log = repo.log();
+        assert_eq!((log[0].author_name.as_str(), log[0].author_email.as_str()), ("John", "john@example.com"));
+        assert_eq!((log[1].author_name.as_str(), log[1].author_email.as_str()), ("gitmap", "gitmap@localhost"));
+    }
+
+    #[test]
+    fn retries_locked_commits() {
         let path = TempDir::new().unwrap().path().to_owned();
         let mut repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.has_key("bar"), false);
-        repo.insert_key("foo", "111".as_bytes()).unwrap();
-        repo.insert_key("bar", "222".as_bytes()).unwrap();
-        repo.insert_key("baz", "333".as_bytes()).unwrap();
         repo.commit("").unwrap();
-        assert_eq!(repo.has_key("bar"), true);
+        let lock = path.join("refs/heads/master.lock");
+        std::fs::write(&lock, "").unwrap();
+        repo.set_lock_retries(0, Duration::from_millis(10));
+        assert_eq!(repo.commit("").unwrap_err().code(), ErrorCode::Locked);
+        repo.set_lock_retries(10, Duration::from_millis(10));
+        let unlock = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            std::fs::remove_file(lock).unwrap();
+        });
+        repo.commit("").unwrap();
+        unlock.join().unwrap();
+        assert_eq!(repo.log().len(), 2);
     }
 
     #[test]
-    fn provides_branches() {
+    fn commits_deterministically() {
+        let sig = Signature::now("John", "john@foo.com").unwrap();
         let path = TempDir::new().unwrap().path().to_owned();
-        let repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.branches().len(), 0);
-        repo.commit("").unwrap(); // initial commit
-        assert_eq!(repo.branches(), ["master"]);
+        let mut repo1 = Repo::init(&path).unwrap();
+        repo1.set_deterministic(1_500_000_000, sig.clone());
+        repo1.insert_key("foo", "1".as_bytes()).unwrap();
+        repo1.insert_key("bar", "2".as_bytes()).unwrap();
+        repo1.alias_key("baz", "foo").unwrap();
+        repo1.alias_key("qux", "bar").unwrap();
+        repo1.commit("data").unwrap();
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo2 = Repo::init(&path).unwrap();
+        repo2.set_deterministic(1_500_000_000, sig);
+        repo2.insert_key("bar", "2".as_bytes()).unwrap();
+        repo2.alias_key("qux", "bar").unwrap();
+        repo2.insert_key("foo", "0".as_bytes()).unwrap();
+        repo2.insert_key("foo", "1".as_bytes()).unwrap();
+        repo2.alias_key("baz", "foo").unwrap();
+        repo2.commit_with_timeout("data", Duration::from_secs(5)).unwrap();
+        assert_eq!(repo1.last_commit_id().unwrap(), repo2.last_commit_id().unwrap());
+        let mut batch = repo1.batch();
+        batch.insert("qux", "3".as_bytes()).unwrap();
+        let batched = batch.commit("more").unwrap();
+        repo2.insert_key("qux", "3".as_bytes()).unwrap();
+        assert_eq!(repo2.commit("more").unwrap(), batched);
+        let deployed = repo1.deploy_to_branch("prod", "deploy", |repo| repo.insert_key("foo", "4".as_bytes())).unwrap();
+        assert_eq!(repo2.deploy_to_branch("prod", "deploy", |repo| repo.insert_key("foo", "4".as_bytes())).unwrap(), deployed);
     }
 
     #[test]
-    fn provides_keys() {
+    fn rolls_back_commits() {
         let path = TempDir::new().unwrap().path().to_owned();
         let mut repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.keys().len(), 0);
-        repo.insert_key("foo", "".as_bytes()).unwrap();
-        repo.insert_key("bar", "".as_bytes()).unwrap();
+        assert_eq!(repo.rollback().is_err(), true); // empty repository
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
         repo.commit("").unwrap();
-        assert_eq!(repo.keys(), ["bar", "foo"]);
+        assert_eq!(repo.rollback().is_err(), true); // no parent
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("bar", "3".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.rollback().unwrap();
+        assert_eq!(repo.keys(), ["foo"]);
+        assert_eq!(String::from_utf8(repo.key("foo").unwrap()).unwrap(), "2");
+        assert_eq!(repo.rollback_by(2).is_err(), true);
+        repo.rollback_by(1).unwrap();
+        assert_eq!(String::from_utf8(repo.key("foo").unwrap()).unwrap(), "1");
     }
 
     #[test]
-    fn provides_current_branch() {
+    fn discards_staged_changes_on_rollback() {
         let path = TempDir::new().unwrap().path().to_owned();
-        let repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.branch().is_none(), true);
-        repo.commit("").unwrap(); // initial commit
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("bar", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("baz", "3".as_bytes()).unwrap();
+        assert_eq!(repo.changed(), true);
+        repo.rollback().unwrap();
+        assert_eq!(repo.changed(), false);
+        assert_eq!(repo.keys(), ["foo"]);
+        let err = repo.rollback().unwrap_err();
+        assert_eq!(err.message(), "history is too short to roll back 1 commit(s)");
+    }
+
+    #[test]
+    fn restores_from_reflog() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.restore_from_reflog("HEAD", 0).is_err(), true);
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("foo", "3".as_bytes()).unwrap();
+        let tip = repo.commit("").unwrap();
+        repo.rollback_by(2).unwrap();
+        assert_eq!(String::from_utf8(repo.key("foo").unwrap()).unwrap(), "1");
+        assert_eq!(repo.restore_from_reflog("refs/heads/master", 1).unwrap(), tip);
+        assert_eq!(String::from_utf8(repo.key("foo").unwrap()).unwrap(), "3");
+        repo.rollback().unwrap();
+        assert_eq!(repo.restore_from_reflog("HEAD", 1).unwrap(), tip);
         assert_eq!(repo.branch().unwrap(), "master");
+        assert_eq!(repo.restore_from_reflog("HEAD", 100).is_err(), true);
     }
-    
+
     #[test]
-    fn provides_key_value() {
+    fn rolls_back_branch_commits() {
         let path = TempDir::new().unwrap().path().to_owned();
         let mut repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.key("bar").is_none(), true);
-        repo.insert_key("foo", "111".as_bytes()).unwrap();
-        repo.insert_key("bar", "222".as_bytes()).unwrap();
-        repo.insert_key("baz", "333".as_bytes()).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
         repo.commit("").unwrap();
-        assert_eq!(String::from_utf8(repo.key("bar").unwrap()).unwrap(), "222");
+        repo.switch_branch("staging").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.rollback().unwrap();
+        assert_eq!(repo.branch().unwrap(), "staging");
+        assert_eq!(String::from_utf8(repo.key("foo").unwrap()).unwrap(), "1");
+        assert_eq!(Repo::read_key(&path, Some("master"), "foo").unwrap().unwrap(), "1".as_bytes());
     }
 
     #[test]
-    fn switches_branch() {
+    fn checks_changes() {
         let path = TempDir::new().unwrap().path().to_owned();
         let mut repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.switch_branch("foo").is_err(), true);
-        repo.commit("").unwrap(); // initial commit
-        assert_eq!(repo.switch_branch("foo").is_ok(), true);
-        assert_eq!(repo.branch().unwrap(), "foo");
+        assert_eq!(repo.changed(), false);
+        repo.insert_key("foo", "".as_bytes()).unwrap();
+        assert_eq!(repo.changed(), true);
+        repo.reset().unwrap();
+        assert_eq!(repo.changed(), false);
     }
 
     #[test]
-    fn removes_branch() {
+    fn provides_key_meta() {
         let path = TempDir::new().unwrap().path().to_owned();
         let mut repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.remove_branch("master").is_err(), true); // no branches
-        repo.commit("").unwrap(); // initial commit
-        assert_eq!(repo.remove_branch("master").is_err(), true); // can't remove working branch
-        repo.switch_branch("foo").unwrap();
-        assert_eq!(repo.branches(), ["foo", "master"]);
-        assert_eq!(repo.remove_branch("foo").is_err(), true); // can't remove working branch
-        repo.switch_branch("master").unwrap();
-        assert_eq!(repo.remove_branch("foo").is_ok(), true);
-        assert_eq!(repo.branches(), ["master"]);
+        assert_eq!(repo.keys_meta().unwrap(), []);
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("bar", "12".as_bytes()).unwrap();
+        assert_eq!(repo.key_meta("foo").unwrap(), Some(KeyMeta { size: 1, modified: None, modified_time: None, created: None }));
+        let first = repo.commit("").unwrap();
+        repo.insert_key("dir/baz", "123".as_bytes()).unwrap();
+        repo.remove_key("bar").unwrap();
+        let second = repo.commit("").unwrap();
+        let second_time = Some(repo.last_commit().unwrap().timestamp);
+        repo.insert_key("foo", "1234".as_bytes()).unwrap();
+        repo.insert_key("bar", "1".as_bytes()).unwrap();
+        let third = repo.commit("").unwrap();
+        let third_time = Some(repo.last_commit().unwrap().timestamp);
+        repo.insert_key("dir/baz", "12".as_bytes()).unwrap();
+        repo.insert_key("qux", "1".as_bytes()).unwrap();
+        repo.alias_key("alias", "foo").unwrap();
+        assert_eq!(repo.keys_meta().unwrap(), [
+            ("bar".to_string(), KeyMeta { size: 1, modified: Some(third), modified_time: third_time, created: Some(first) }),
+            ("dir/baz".to_string(), KeyMeta { size: 2, modified: None, modified_time: None, created: Some(second) }),
+            ("foo".to_string(), KeyMeta { size: 4, modified: Some(third), modified_time: third_time, created: Some(first) }),
+            ("qux".to_string(), KeyMeta { size: 1, modified: None, modified_time: None, created: None }),
+        ]);
+        assert_eq!(repo.key_meta("alias").unwrap(), repo.key_meta("foo").unwrap());
+        assert_eq!(repo.key_meta("missing").unwrap(), None);
+        repo.reset().unwrap();
+        assert_eq!(repo.key_meta("dir/baz").unwrap(), Some(KeyMeta { size: 3, modified: Some(second), modified_time: second_time, created: Some(second) }));
     }
 
     #[test]
-    fn performs_operations() {
+    fn counts_changed_keys() {
         let path = TempDir::new().unwrap().path().to_owned();
         let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.changed_count(), 0);
         repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("bar", "1".as_bytes()).unwrap();
+        repo.alias_key("qux", "foo").unwrap();
+        assert_eq!(repo.changed_count(), 2);
         repo.commit("").unwrap();
-        assert_eq!(repo.keys(), ["foo"]);
-        repo.insert_key("foo", "11".as_bytes()).unwrap();
-        repo.insert_key("bar", "2".as_bytes()).unwrap();
+        assert_eq!(repo.changed_count(), 0);
+        repo.alias_key("quux", "bar").unwrap();
+        assert_eq!(repo.changed_count(), 0);
+        assert_eq!(repo.changed(), false);
+        repo.reset().unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.insert_key("dir/baz", "1".as_bytes()).unwrap();
+        repo.insert_key("dir/qux", "1".as_bytes()).unwrap();
+        repo.remove_key("bar").unwrap();
+        repo.remove_alias("qux").unwrap();
+        assert_eq!(repo.changed_count(), 4);
+        assert_eq!(repo.changed_count(), repo.staged_changes().unwrap().len());
+    }
+
+    #[test]
+    fn provides_staged_changes() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.staged_changes().unwrap(), []);
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("bar", "1".as_bytes()).unwrap();
+        repo.alias_key("qux", "foo").unwrap();
+        assert_eq!(repo.staged_changes().unwrap(), [KeyChange::Added("bar".to_string()), KeyChange::Added("foo".to_string())]);
         repo.commit("").unwrap();
-        assert_eq!(repo.keys(), ["bar", "foo"]);
-        repo.remove_key("foo").unwrap();
-        repo.insert_key("bar", "22".as_bytes()).unwrap();
-        repo.reset_key("bar").unwrap();
+        assert_eq!(repo.staged_changes().unwrap(), []);
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.remove_key("bar").unwrap();
+        repo.insert_key("baz/a", "1".as_bytes()).unwrap();
+        assert_eq!(repo.staged_changes().unwrap(), [
+            KeyChange::Removed("bar".to_string()),
+            KeyChange::Added("baz/a".to_string()),
+            KeyChange::Modified("foo".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn diffs_branches() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("same", "1".as_bytes()).unwrap();
+        repo.insert_key("changed", "1".as_bytes()).unwrap();
+        repo.insert_key("dropped", "1".as_bytes()).unwrap();
         repo.commit("").unwrap();
-        assert_eq!(repo.keys(), ["bar"]);
-        assert_eq!(String::from_utf8(repo.key("bar").unwrap()).unwrap(), "2");
+        repo.switch_branch("staging").unwrap();
+        repo.insert_key("changed", "2".as_bytes()).unwrap();
+        repo.remove_key("dropped").unwrap();
+        repo.insert_key("dir/new", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.diff_branches("master", "staging").unwrap(), [
+            KeyChange::Modified("changed".to_string()),
+            KeyChange::Added("dir/new".to_string()),
+            KeyChange::Removed("dropped".to_string()),
+        ]);
+        assert_eq!(repo.diff_branches("staging", "staging").unwrap(), []);
+        assert_eq!(repo.diff_branches("master", "missing").is_err(), true);
+        assert_eq!(repo.diff_branches("missing", "master").is_err(), true);
+    }
+
+    #[test]
+    fn previews_insert_diff() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        let diff = repo.preview_insert_diff("foo", "a\nb\n".as_bytes()).unwrap();
+        assert_eq!(diff.contains("+a\n+b\n"), true);
+        repo.insert_key("foo", "a\nb\n".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        let diff = repo.preview_insert_diff("foo", "a\nc\n".as_bytes()).unwrap();
+        assert_eq!(diff.contains("--- a/foo\n+++ b/foo\n"), true);
+        assert_eq!(diff.contains(" a\n-b\n+c\n"), true);
+        assert_eq!(repo.preview_insert_diff("foo", "a\nb\n".as_bytes()).unwrap(), "");
+        assert_eq!(repo.preview_insert_diff("foo", &[0, 1, 2]).unwrap(), "binary");
+    }
+
+    #[test]
+    fn reports_read_errors() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.try_keys().unwrap(), Vec::<String>::new());
+        assert_eq!(repo.try_key("foo").unwrap(), None);
+        assert_eq!(repo.try_branches().unwrap(), Vec::<String>::new());
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.try_key("foo").unwrap().unwrap(), "1".as_bytes());
+        assert_eq!(repo.try_key("bar").unwrap(), None);
+        assert_eq!(repo.try_changed().unwrap(), false);
+        assert_eq!(repo.try_key_changed("foo").unwrap(), false);
+        let tree_id = repo.committed_tree_oid().unwrap().to_string();
+        std::fs::remove_file(path.join("objects").join(&tree_id[..2]).join(&tree_id[2..])).unwrap();
+        let repo = Repo::open(&path).unwrap(); // drops cached objects
+        assert_eq!(repo.try_keys().is_err(), true);
+        assert_eq!(repo.keys(), Vec::<String>::new());
+        assert_eq!(repo.try_key("foo").is_err(), true);
+        assert_eq!(repo.key("foo"), None);
+        assert_eq!(repo.try_changed().is_err(), true);
+        assert_eq!(repo.try_key_changed("foo").is_err(), true);
+        assert_eq!(Repo::open(path.join("missing")).is_err(), true);
+    }
+
+    #[test]
+    fn provides_entries() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("bar/baz", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("qux", "".as_bytes()).unwrap();
+        repo.alias_key("alias", "foo").unwrap();
+        let entries = repo.entries().unwrap();
+        let expected: Vec<(String, Vec<u8>)> = repo.keys().into_iter().map(|name| {
+            let value = repo.key(&name).unwrap();
+            (name, value)
+        }).collect();
+        assert_eq!(entries, expected);
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[test]
+    fn iterates_entries() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("bar/baz", "2".as_bytes()).unwrap();
+        repo.alias_key("qux", "foo").unwrap();
+        let mut names = Vec::new();
+        repo.for_each_entry(|name, entry| {
+            assert_eq!(entry.filemode, 0o100644);
+            names.push(name.to_string());
+            ControlFlow::Continue(())
+        }).unwrap();
+        assert_eq!(names, ["bar/baz", "foo"]);
+        let mut count = 0;
+        repo.for_each_entry(|_, _| {
+            count += 1;
+            ControlFlow::Break(())
+        }).unwrap();
+        assert_eq!(count, 1);
         repo.remove().unwrap();
+        assert_eq!(repo.keys(), Vec::<String>::new());
+        assert_eq!(repo.aliases().len(), 0);
+    }
+
+    #[test]
+    fn provides_metrics() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.metrics().unwrap(), StoreMetrics::default());
+        repo.insert_key("foo", "12".as_bytes()).unwrap();
+        repo.insert_key("dir/bar", "345".as_bytes()).unwrap();
         repo.commit("").unwrap();
-        assert_eq!(repo.keys().len(), 0);
+        repo.switch_branch("staging").unwrap();
+        repo.alias_key("baz", "foo").unwrap();
+        let second = repo.commit("").unwrap();
+        repo.insert_key("qux", "6".as_bytes()).unwrap();
+        let metrics = repo.metrics().unwrap();
+        assert_eq!((metrics.keys, metrics.value_bytes, metrics.commits, metrics.branches), (3, 6, 2, 2));
+        assert_eq!(metrics.last_commit_time, Some(repo.repo.find_commit(second).unwrap().time().seconds()));
     }
 
     #[test]
-    fn checks_changes() {
+    fn provides_value_size_histogram() {
         let path = TempDir::new().unwrap().path().to_owned();
         let mut repo = Repo::init(&path).unwrap();
-        assert_eq!(repo.changed(), false);
-        repo.insert_key("foo", "".as_bytes()).unwrap();
-        assert_eq!(repo.changed(), true);
+        assert_eq!(repo.value_size_histogram(&[10]).unwrap().buckets, [(10, 0), (u64::MAX, 0)]);
+        repo.insert_key("empty", "".as_bytes()).unwrap();
+        repo.insert_key("small", "12345".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("dir/edge", "1234567890".as_bytes()).unwrap();
+        repo.insert_key("dir/large", &[0; 100]).unwrap();
+        repo.alias_key("alias", "dir/large").unwrap();
+        let histogram = repo.value_size_histogram(&[10, 0]).unwrap();
+        assert_eq!(histogram.buckets, [(0, 1), (10, 2), (u64::MAX, 1)]);
+        assert_eq!(histogram.zero_sized, 1);
+        assert_eq!(histogram.max_size, 100);
+    }
+
+    #[test]
+    fn reports_staged_data() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "123".as_bytes()).unwrap();
+        repo.insert_key("bar", "123".as_bytes()).unwrap();
+        assert_eq!(repo.stage_report().unwrap(), StageReport { new_blobs: 1, new_bytes: 3, dedup_hits: 1, unchanged: 0 });
+        repo.commit("").unwrap();
+        assert_eq!(repo.stage_report().unwrap(), StageReport::default());
+        repo.insert_key("foo", "123".as_bytes()).unwrap(); // no-op
+        repo.insert_key("baz", "123".as_bytes()).unwrap(); // known value
+        repo.insert_key("bar", "12345".as_bytes()).unwrap(); // new value
+        repo.remove_key("qux").unwrap();
+        assert_eq!(repo.stage_report().unwrap(), StageReport { new_blobs: 1, new_bytes: 5, dedup_hits: 1, unchanged: 1 });
         repo.reset().unwrap();
-        assert_eq!(repo.changed(), false);
+        assert_eq!(repo.stage_report().unwrap(), StageReport::default());
     }
 
     #[test]
@@ -0,0 +1,304 @@
+use std::collections::{BTreeMap, BTreeSet};
+use git2::{BranchType, Delta, ErrorCode, ErrorClass, Oid, Tree};
+use crate::{Repo, GitmapError, Ring, ALIASES_META, ROTATING_META};
+use crate::{decode_aliases, decode_rings, encode_aliases, encode_rings, is_meta};
+
+/// Blob changes by path, `None` marking removed paths, and conflicting names.
+type MergedChanges = (Vec<(String, Option<Oid>)>, Vec<String>);
+
+/// Result of merging a branch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeOutcome {
+    /// The working branch already contains the merged branch.
+    UpToDate,
+    /// The working branch was moved to the contained commit.
+    FastForward(Oid),
+    /// A merge commit with the contained ID was created.
+    Merged(Oid),
+    /// Both branches changed the contained keys differently so nothing was
+    /// committed.
+    Conflicts(Vec<String>),
+}
+
+/// Describes how keys changed differently on both branches are resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// The value of the working branch wins.
+    Ours,
+    /// The value of the merged branch wins.
+    Theirs,
+}
+
+/// Merge functions.
+impl Repo {
+
+    /// Merges the named branch into the working branch. Fast-forwards when
+    /// possible and otherwise merges keys changed since the common ancestor,
+    /// reporting keys changed differently on both branches as conflicts.
+    /// With the `crdt` feature replicated counters and sets changed on both
+    /// branches are combined instead. Errors when changes are staged.
+    pub fn merge_branch(&mut self, name: &str, message: &str) -> Result<MergeOutcome, GitmapError> {
+        self.merge(name, message, None)
+    }
+
+    /// Merges the named branch like `merge_branch` but resolves conflicts
+    /// with the provided strategy. Values are never merged line by line.
+    pub fn merge_branch_with(&mut self, name: &str, message: &str, strategy: MergeStrategy) -> Result<MergeOutcome, GitmapError> {
+        self.merge(name, message, Some(strategy))
+    }
+
+    /// Merges the named branch resolving conflicts with the strategy if any.
+    fn merge(&mut self, name: &str, message: &str, strategy: Option<MergeStrategy>) -> Result<MergeOutcome, GitmapError> {
+        if self.tree_changed()? {
+            return Err(GitmapError::new(ErrorCode::Uncommitted, ErrorClass::Merge, "commit or reset staged changes before merging"));
+        }
+        let theirs = self.repo.find_branch(name, BranchType::Local)?.get().peel_to_commit()?.id();
+        let ours = match self.has_commits() {
+            true => self.last_commit_id()?,
+            false => return self.fast_forward_head(theirs),
+        };
+        if ours == theirs || self.repo.graph_descendant_of(ours, theirs)? {
+            return Ok(MergeOutcome::UpToDate);
+        }
+        if self.repo.graph_descendant_of(theirs, ours)? {
+            return self.fast_forward_head(theirs);
+        }
+        let (tree_id, parents) = {
+            let (ours, theirs) = (self.repo.find_commit(ours)?, self.repo.find_commit(theirs)?);
+            let base = self.repo.find_commit(self.repo.merge_base(ours.id(), theirs.id())?)?.tree()?;
+            let (our_tree, their_tree) = (ours.tree()?, theirs.tree()?);
+            let our_changes = self.tree_changes(&base, &our_tree)?;
+            let (mut changes, mut conflicts) = self.merge_meta(&base, &our_tree, &their_tree, strategy)?;
+            for (path, blob) in self.tree_changes(&base, &their_tree)? {
+                #[cfg(feature = "crdt")]
+                if let (Some(Some(ours)), Some(theirs)) = (our_changes.get(&path), blob) {
+                    if let Some(merged) = self.merge_replicated(*ours, theirs)? {
+                        changes.push((path, Some(merged)));
+                        continue;
+                    }
+                }
+                match (our_changes.get(&path), strategy) {
+                    (None, _) | (Some(_), Some(MergeStrategy::Theirs)) => changes.push((path, blob)),
+                    (Some(ours), _) if *ours == blob => {},
+                    (Some(_), Some(MergeStrategy::Ours)) => {},
+                    (Some(_), None) => conflicts.push(path),
+                }
+            }
+            if !conflicts.is_empty() {
+                return Ok(MergeOutcome::Conflicts(conflicts));
+            }
+            let changes: Vec<(&str, Option<Oid>)> = changes.iter().map(|(path, blob)| (path.as_str(), *blob)).collect();
+            (self.update_tree(Some(ours.tree_id()), &changes)?, [ours.id(), theirs.id()])
+        };
+        let id = self.commit_tree_to("HEAD", &parents, tree_id, message, &self.signature()?)?;
+        self.set_staged_tree(None);
+        Ok(MergeOutcome::Merged(id))
+    }
+
+    /// Moves the working branch to the commit and discards the staged tree.
+    fn fast_forward_head(&mut self, id: Oid) -> Result<MergeOutcome, GitmapError> {
+        let refname = match self.repo.find_reference("HEAD")?.symbolic_target() {
+            Some(refname) => refname.to_string(),
+            None => return Err(GitmapError::new(ErrorCode::Invalid, ErrorClass::Reference, "HEAD is detached")),
+        };
+        self.retry_locked(|| Ok(self.repo.reference(&refname, id, true, "merge: fast-forward")?))?;
+        self.set_staged_tree(None);
+        Ok(MergeOutcome::FastForward(id))
+    }
+
+    /// Maps paths changed between the trees to their new blob IDs, `None`
+    /// marking removed paths.
+    fn tree_changes(&self, old: &Tree, new: &Tree) -> Result<BTreeMap<String, Option<Oid>>, GitmapError> {
+        let mut changes = BTreeMap::new();
+        for delta in self.repo.diff_tree_to_tree(Some(old), Some(new), None)?.deltas() {
+            let (file, blob) = match delta.status() {
+                Delta::Deleted => (delta.old_file(), None),
+                _ => (delta.new_file(), Some(delta.new_file().id())),
+            };
+            if let Some(path) = file.path().and_then(|p| p.to_str()).filter(|path| !is_meta(path)) {
+                changes.insert(path.to_string(), blob);
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Merges aliases and rotating key state changed on the merged branch
+    /// entry by entry. Returns the metadata changes and the aliases pointing
+    /// at different keys on both branches. Rotating state follows the working
+    /// branch unless the strategy says otherwise since its slots are merged as
+    /// keys.
+    fn merge_meta(&self, base: &Tree, ours: &Tree, theirs: &Tree, strategy: Option<MergeStrategy>) -> Result<MergedChanges, GitmapError> {
+        let mut changes = Vec::new();
+        let mut conflicts = Vec::new();
+        for name in [ALIASES_META, ROTATING_META] {
+            let [base, ours, theirs] = [base, ours, theirs].map(|tree| self.try_tree_key(tree.id(), name));
+            let (base, ours, theirs) = (base?.unwrap_or_default(), ours?.unwrap_or_default(), theirs?.unwrap_or_default());
+            let data = if theirs == base || theirs == ours {
+                continue;
+            } else if ours == base {
+                theirs
+            } else if name == ALIASES_META {
+                let (aliases, clashes) = merge_records(decode_aliases(&base), decode_aliases(&ours), decode_aliases(&theirs), strategy);
+                conflicts.extend(clashes);
+                encode_aliases(&aliases)
+            } else {
+                let records = |data: &[u8]| -> Result<Vec<(String, Ring)>, GitmapError> {
+                    Ok(decode_rings(data)?.into_iter().map(|ring| (ring.prefix.clone(), ring)).collect())
+                };
+                let strategy = Some(strategy.unwrap_or(MergeStrategy::Ours));
+                let (rings, _) = merge_records(records(&base)?, records(&ours)?, records(&theirs)?, strategy);
+                encode_rings(&rings.into_iter().map(|(_, ring)| ring).collect::<Vec<Ring>>())
+            };
+            let blob = match data.is_empty() {
+                true => None,
+                false => Some(self.write_blob(&data)?),
+            };
+            changes.push((name.to_string(), blob));
+        }
+        Ok((changes, conflicts))
+    }
+}
+
+/// Merges named records changed on both branches since the base. Returns the
+/// merged records sorted by name and the names changed differently on both
+/// branches, which keep the working branch value without a strategy.
+fn merge_records<T: Clone + PartialEq>(base: Vec<(String, T)>, ours: Vec<(String, T)>, theirs: Vec<(String, T)>, strategy: Option<MergeStrategy>) -> (Vec<(String, T)>, Vec<String>) {
+    let base: BTreeMap<String, T> = base.into_iter().collect();
+    let ours: BTreeMap<String, T> = ours.into_iter().collect();
+    let theirs: BTreeMap<String, T> = theirs.into_iter().collect();
+    let mut merged = Vec::new();
+    let mut conflicts = Vec::new();
+    for name in ours.keys().chain(theirs.keys()).collect::<BTreeSet<_>>() {
+        let (old, ours, theirs) = (base.get(name), ours.get(name), theirs.get(name));
+        let value = if ours == theirs || theirs == old {
+            ours
+        } else if ours == old {
+            theirs
+        } else {
+            match strategy {
+                Some(MergeStrategy::Theirs) => theirs,
+                Some(MergeStrategy::Ours) => ours,
+                None => {
+                    conflicts.push(name.clone());
+                    ours
+                },
+            }
+        };
+        if let Some(value) = value {
+            merged.push((name.clone(), value.clone()));
+        }
+    }
+    (merged, conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+
+    /// Returns a repository with `foo` and `bar` committed on `master` and a
+    /// `draft` branch pointing at the same commit.
+    fn repo_with_draft() -> Repo {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.insert_key("bar", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.switch_branch("draft").unwrap();
+        repo.reset().unwrap();
+        repo
+    }
+
+    #[test]
+    fn fast_forwards_merge() {
+        let mut repo = repo_with_draft();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        let draft = repo.commit("").unwrap();
+        repo.switch_branch("master").unwrap();
+        repo.reset().unwrap();
+        assert_eq!(repo.merge_branch("draft", "").unwrap(), MergeOutcome::FastForward(draft));
+        assert_eq!(repo.key("foo"), Some("2".as_bytes().to_vec()));
+        assert_eq!(repo.merge_branch("draft", "").unwrap(), MergeOutcome::UpToDate);
+        assert_eq!(repo.merge_branch("missing", "").is_err(), true);
+        repo.alias_key("qux", "foo").unwrap();
+        assert_eq!(repo.merge_branch("draft", "").is_err(), true);
+        repo.reset().unwrap();
+        repo.insert_key("baz", "1".as_bytes()).unwrap();
+        assert_eq!(repo.merge_branch("draft", "").is_err(), true);
+    }
+
+    #[test]
+    fn merges_disjoint_changes() {
+        let mut repo = repo_with_draft();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.insert_key("dir/baz", "1".as_bytes()).unwrap();
+        let draft = repo.commit("").unwrap();
+        repo.switch_branch("master").unwrap();
+        repo.reset().unwrap();
+        repo.remove_key("bar").unwrap();
+        repo.insert_key("qux", "1".as_bytes()).unwrap();
+        let master = repo.commit("").unwrap();
+        let id = match repo.merge_branch("draft", "merged").unwrap() {
+            MergeOutcome::Merged(id) => id,
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        };
+        let commit = repo.repo.find_commit(id).unwrap();
+        assert_eq!(commit.parent_ids().collect::<Vec<_>>(), [master, draft]);
+        assert_eq!(repo.keys(), ["dir/baz", "foo", "qux"]);
+        assert_eq!(repo.key("foo"), Some("2".as_bytes().to_vec()));
+        assert_eq!(repo.changed(), false);
+        assert_eq!(repo.log()[0].message, "merged");
+    }
+
+    #[test]
+    fn merges_metadata() {
+        let mut repo = repo_with_draft();
+        repo.alias_key("one", "foo").unwrap();
+        repo.alias_key("both", "foo").unwrap();
+        repo.insert_rotating("log.", "1".as_bytes(), 2).unwrap();
+        repo.commit("").unwrap();
+        repo.switch_branch("master").unwrap();
+        repo.reset().unwrap();
+        repo.alias_key("two", "bar").unwrap();
+        repo.alias_key("both", "foo").unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(matches!(repo.merge_branch("draft", "").unwrap(), MergeOutcome::Merged(_)), true);
+        assert_eq!(repo.aliases(), [("both".to_string(), "foo".to_string()), ("one".to_string(), "foo".to_string()), ("two".to_string(), "bar".to_string())]);
+        assert_eq!(repo.rotating_entries("log."), ["log.0"]);
+        repo.switch_branch("draft").unwrap();
+        repo.reset().unwrap();
+        repo.alias_key("one", "bar").unwrap();
+        repo.commit("").unwrap();
+        repo.switch_branch("master").unwrap();
+        repo.reset().unwrap();
+        repo.insert_key("baz", "1".as_bytes()).unwrap();
+        repo.alias_key("one", "baz").unwrap();
+        repo.alias_key("two", "foo").unwrap();
+        let master = repo.commit("").unwrap();
+        assert_eq!(repo.merge_branch("draft", "").unwrap(), MergeOutcome::Conflicts(vec!["one".to_string()]));
+        assert_eq!(repo.head_oid().unwrap(), master);
+        assert_eq!(matches!(repo.merge_branch_with("draft", "", MergeStrategy::Theirs).unwrap(), MergeOutcome::Merged(_)), true);
+        assert_eq!(repo.aliases(), [("both".to_string(), "foo".to_string()), ("one".to_string(), "bar".to_string()), ("two".to_string(), "foo".to_string())]);
+    }
+
+    #[test]
+    fn reports_merge_conflicts() {
+        let mut repo = repo_with_draft();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.insert_key("bar", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.switch_branch("master").unwrap();
+        repo.reset().unwrap();
+        repo.insert_key("foo", "3".as_bytes()).unwrap();
+        repo.insert_key("bar", "2".as_bytes()).unwrap();
+        let master = repo.commit("").unwrap();
+        assert_eq!(repo.merge_branch("draft", "").unwrap(), MergeOutcome::Conflicts(vec!["foo".to_string()]));
+        assert_eq!(repo.head_oid().unwrap(), master);
+        assert_eq!(matches!(repo.merge_branch_with("draft", "", MergeStrategy::Ours).unwrap(), MergeOutcome::Merged(_)), true);
+        assert_eq!(repo.key("foo"), Some("3".as_bytes().to_vec()));
+        repo.rollback().unwrap();
+        assert_eq!(matches!(repo.merge_branch_with("draft", "", MergeStrategy::Theirs).unwrap(), MergeOutcome::Merged(_)), true);
+        assert_eq!(repo.key("foo"), Some("2".as_bytes().to_vec()));
+        assert_eq!(repo.key("bar"), Some("2".as_bytes().to_vec()));
+    }
+}
@@ -0,0 +1,215 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use git2::{ErrorCode, ErrorClass};
+use crate::{Repo, GitmapError, Oid};
+
+/// Largest multiple of the interval waited after repeated failures.
+const MAX_BACKOFF_FACTOR: u32 = 32;
+
+/// Branch moved by a mirror refresh.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BranchUpdate {
+    /// Branch name.
+    pub branch: String,
+    /// Commit the branch pointed to before, `None` when it was created.
+    pub old: Option<Oid>,
+    /// Commit the branch points to now.
+    pub new: Oid,
+}
+
+/// Outcome of a mirror refresh passed to the callback.
+#[derive(Debug)]
+pub enum MirrorEvent {
+    /// Branches moved by the refresh.
+    Updated(Vec<BranchUpdate>),
+    /// Refresh of the branch failed and is retried with backoff.
+    Failed {
+        /// Branch name.
+        branch: String,
+        /// Fetch error.
+        error: GitmapError,
+    },
+}
+
+/// State of a mirror runner.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MirrorStatus {
+    /// Whether the refresh thread is running.
+    pub running: bool,
+    /// Number of completed refresh rounds.
+    pub rounds: usize,
+    /// Number of consecutive rounds with a failure.
+    pub failures: usize,
+    /// Message of the last failure.
+    pub last_error: Option<String>,
+}
+
+/// Periodically fetches branches from a remote into a read-only mirror. The
+/// runner owns the repository while running and hands it back on `stop`.
+pub struct MirrorRunner {
+    repo: Option<Repo>,
+    remote: String,
+    interval: Duration,
+    branches: Vec<String>,
+    status: Arc<Mutex<MirrorStatus>>,
+    worker: Option<(Sender<()>, JoinHandle<Repo>)>,
+}
+
+impl MirrorRunner {
+
+    /// Returns a stopped runner refreshing the local branches of the
+    /// repository from the remote every `interval`.
+    pub fn new(repo: Repo, remote: &str, interval: Duration) -> Self {
+        let branches = repo.branches();
+        Self {
+            repo: Some(repo),
+            remote: remote.to_string(),
+            interval,
+            branches,
+            status: Arc::new(Mutex::new(MirrorStatus::default())),
+            worker: None,
+        }
+    }
+
+    /// Sets the branches refreshed from the remote. Missing local branches
+    /// are created on the first refresh.
+    pub fn set_branches(&mut self, branches: &[&str]) {
+        self.branches = branches.iter().map(|branch| branch.to_string()).collect();
+    }
+
+    /// Starts refreshing on a background thread, reporting every change and
+    /// failure to the callback. Failed rounds are retried after a doubled
+    /// interval. Errors when the runner is already running.
+    pub fn start<F>(&mut self, mut callback: F) -> Result<(), GitmapError>
+    where
+        F: FnMut(MirrorEvent) + Send + 'static,
+    {
+        let mut repo = match self.repo.take() {
+            Some(repo) => repo,
+            None => return Err(GitmapError::new(ErrorCode::Exists, ErrorClass::Thread, "mirror runner is already running")),
+        };
+        let (remote, interval, branches, status) = (self.remote.clone(), self.interval, self.branches.clone(), self.status.clone());
+        let (stop, stopped) = mpsc::channel();
+        status.lock().unwrap_or_else(|err| err.into_inner()).running = true;
+        let handle = thread::spawn(move || {
+            let mut delay = Duration::from_secs(0);
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(delay) {
+                let mut updates = Vec::new();
+                let mut failure = None;
+                for branch in &branches {
+                    let old = repo.branch_oid(branch).ok();
+                    match repo.fetch(&remote, branch).and_then(|_| repo.branch_oid(branch)) {
+                        Ok(new) if old != Some(new) => updates.push(BranchUpdate { branch: branch.clone(), old, new }),
+                        Ok(_) => {},
+                        Err(error) => {
+                            failure = Some(error.message().to_string());
+                            callback(MirrorEvent::Failed { branch: branch.clone(), error });
+                        },
+                    }
+                }
+                if !updates.is_empty() {
+                    callback(MirrorEvent::Updated(updates));
+                }
+                let mut status = status.lock().unwrap_or_else(|err| err.into_inner());
+                status.rounds += 1;
+                delay = match failure {
+                    Some(message) => {
+                        status.failures += 1;
+                        status.last_error = Some(message);
+                        interval * 2u32.saturating_pow(status.failures as u32).min(MAX_BACKOFF_FACTOR)
+                    },
+                    None => {
+                        status.failures = 0;
+                        interval
+                    },
+                };
+            }
+            repo
+        });
+        self.worker = Some((stop, handle));
+        Ok(())
+    }
+
+    /// Returns the current state of the runner.
+    pub fn status(&self) -> MirrorStatus {
+        self.status.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+
+    /// Stops refreshing after the running round completes and returns the
+    /// repository. Errors when the callback panicked.
+    pub fn stop(mut self) -> Result<Repo, GitmapError> {
+        let repo = match self.worker.take() {
+            Some((stop, handle)) => {
+                let _ = stop.send(());
+                handle.join().map_err(|_| GitmapError::new(ErrorCode::GenericError, ErrorClass::Thread, "mirror runner thread panicked"))
+            },
+            None => Ok(self.repo.take().expect("stopped runner holds the repository")),
+        };
+        self.status.lock().unwrap_or_else(|err| err.into_inner()).running = false;
+        repo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use tempfile::TempDir;
+    use super::*;
+
+    /// Returns the URL of the repository at the provided path.
+    fn url(path: &Path) -> String {
+        format!("file://{}", path.display())
+    }
+
+    #[test]
+    fn mirrors_remote_branches() {
+        let remote_path = TempDir::new().unwrap().path().to_owned();
+        let mut remote = Repo::init(&remote_path).unwrap();
+        remote.insert_key("foo", "1".as_bytes()).unwrap();
+        let first = remote.commit("").unwrap();
+        let path = TempDir::new().unwrap().path().to_owned();
+        let repo = Repo::clone(&url(&remote_path), &path).unwrap();
+        let mut runner = MirrorRunner::new(repo, "origin", Duration::from_millis(10));
+        let (events, received) = mpsc::channel();
+        runner.start(move |event| events.send(event).unwrap()).unwrap();
+        assert_eq!(runner.start(|_| {}).is_err(), true);
+        remote.insert_key("foo", "2".as_bytes()).unwrap();
+        let second = remote.commit("").unwrap();
+        match received.recv_timeout(Duration::from_secs(10)).unwrap() {
+            MirrorEvent::Updated(updates) => assert_eq!(updates, [BranchUpdate { branch: "master".to_string(), old: Some(first), new: second }]),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(runner.status().running, true);
+        assert_eq!(runner.status().failures, 0);
+        let repo = runner.stop().unwrap();
+        assert_eq!(repo.key("foo"), Some("2".as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn reports_mirror_failures() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        let mut runner = MirrorRunner::new(repo, "missing", Duration::from_millis(1));
+        runner.set_branches(&["master", "main"]);
+        let (events, received) = mpsc::channel();
+        runner.start(move |event| events.send(event).unwrap()).unwrap();
+        for branch in ["master", "main"] {
+            match received.recv_timeout(Duration::from_secs(10)).unwrap() {
+                MirrorEvent::Failed { branch: failed, .. } => assert_eq!(failed, branch),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        while runner.status().rounds == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let status = runner.status();
+        assert_eq!(status.failures > 0, true);
+        assert_eq!(status.last_error.is_some(), true);
+        let repo = runner.stop().unwrap();
+        assert_eq!(repo.keys(), ["foo"]);
+    }
+}
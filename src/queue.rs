@@ -0,0 +1,153 @@
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use git2::{ErrorCode, ErrorClass};
+use crate::{Repo, GitmapError, Oid};
+
+/// Number of times a submission is reapplied when the branch moved or its
+/// ref was locked while committing.
+const QUEUE_RETRIES: usize = 5;
+
+/// Key change applied by a `CommitQueue` submission.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyOp {
+    /// Sets the key to the value.
+    Insert(String, Vec<u8>),
+    /// Removes the key.
+    Remove(String),
+}
+
+/// Message sent to the queue thread.
+enum Message {
+    Submit(Vec<KeyOp>, String, Sender<Result<Oid, GitmapError>>),
+    Stop,
+}
+
+/// Serializes commits of many writers to the working branch. The queue owns
+/// the repository on a dedicated thread and commits submissions one at a
+/// time in arrival order.
+pub struct CommitQueue {
+    sender: Sender<Message>,
+    worker: JoinHandle<Repo>,
+}
+
+/// Cloneable handle submitting changes to a `CommitQueue`.
+#[derive(Clone)]
+pub struct QueueHandle {
+    sender: Sender<Message>,
+}
+
+impl CommitQueue {
+
+    /// Starts the queue thread. Staged changes of the repository are
+    /// discarded.
+    pub fn new(mut repo: Repo) -> Self {
+        let (sender, received) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            for message in received {
+                match message {
+                    Message::Submit(ops, message, reply) => {
+                        let _ = reply.send(apply(&mut repo, &ops, &message));
+                    },
+                    Message::Stop => break,
+                }
+            }
+            repo
+        });
+        Self { sender, worker }
+    }
+
+    /// Returns a handle submitting changes to the queue.
+    pub fn handle(&self) -> QueueHandle {
+        QueueHandle { sender: self.sender.clone() }
+    }
+
+    /// Stops the queue after the submissions received so far are committed
+    /// and returns the repository. Later submissions fail. Errors when the
+    /// queue thread panicked.
+    pub fn stop(self) -> Result<Repo, GitmapError> {
+        let _ = self.sender.send(Message::Stop);
+        self.worker.join().map_err(|_| GitmapError::new(ErrorCode::GenericError, ErrorClass::Thread, "commit queue thread panicked"))
+    }
+}
+
+impl QueueHandle {
+
+    /// Submits the changes and blocks until they are committed on top of the
+    /// latest branch tip. Returns the new commit ID. A failing submission
+    /// commits nothing and does not affect later ones. Errors when the queue
+    /// is stopped.
+    pub fn submit(&self, ops: Vec<KeyOp>, message: String) -> Result<Oid, GitmapError> {
+        let stopped = || GitmapError::new(ErrorCode::GenericError, ErrorClass::Thread, "commit queue is stopped");
+        let (reply, replied) = mpsc::channel();
+        self.sender.send(Message::Submit(ops, message, reply)).map_err(|_| stopped())?;
+        replied.recv().map_err(|_| stopped())?
+    }
+}
+
+/// Applies the changes to the branch tip and commits them, starting over
+/// when the branch moves meanwhile. Staged changes are always discarded.
+fn apply(repo: &mut Repo, ops: &[KeyOp], message: &str) -> Result<Oid, GitmapError> {
+    let mut attempt = 0;
+    loop {
+        repo.reset()?;
+        let result = ops.iter()
+            .try_for_each(|op| match op {
+                KeyOp::Insert(name, value) => repo.insert_key(name, value),
+                KeyOp::Remove(name) => repo.remove_key(name),
+            })
+            .and_then(|_| repo.commit(message));
+        match result {
+            Err(err) if matches!(err.code(), ErrorCode::Modified | ErrorCode::Locked) && attempt < QUEUE_RETRIES => attempt += 1,
+            result => {
+                repo.reset()?;
+                return result;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn commits_submissions_in_order() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let queue = CommitQueue::new(Repo::init(&path).unwrap());
+        let writers: Vec<_> = (0..4).map(|writer| {
+            let handle = queue.handle();
+            thread::spawn(move || {
+                for i in 0..5 {
+                    let ops = vec![KeyOp::Insert(format!("w{}/{}", writer, i), i.to_string().into_bytes()), KeyOp::Insert("last".to_string(), writer.to_string().into_bytes())];
+                    handle.submit(ops, format!("{}-{}", writer, i)).unwrap();
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let repo = queue.stop().unwrap();
+        assert_eq!(repo.len(), 21);
+        assert_eq!(repo.log().len(), 20);
+        let last = repo.log()[0].message.split('-').next().unwrap().to_string();
+        assert_eq!(repo.key_str("last").unwrap(), last);
+    }
+
+    #[test]
+    fn isolates_failed_submissions() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.set_max_value_size(2);
+        let queue = CommitQueue::new(repo);
+        let handle = queue.handle();
+        let first = handle.submit(vec![KeyOp::Insert("foo".to_string(), b"1".to_vec())], "".to_string()).unwrap();
+        let ops = vec![KeyOp::Insert("bar".to_string(), b"1".to_vec()), KeyOp::Insert("baz".to_string(), b"too large".to_vec())];
+        assert_eq!(handle.submit(ops, "".to_string()).is_err(), true);
+        let second = handle.submit(vec![KeyOp::Remove("foo".to_string()), KeyOp::Insert("qux".to_string(), b"2".to_vec())], "".to_string()).unwrap();
+        let repo = queue.stop().unwrap();
+        assert_eq!(handle.submit(Vec::new(), "".to_string()).is_err(), true);
+        assert_eq!(repo.keys(), ["qux"]);
+        assert_eq!(repo.log().iter().map(|commit| commit.oid).collect::<Vec<_>>(), [second, first]);
+    }
+}
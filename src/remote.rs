@@ -0,0 +1,352 @@
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+use git2::{BranchType, FetchOptions, PushOptions, RemoteCallbacks, Repository, ErrorCode, ErrorClass};
+use git2::build::RepoBuilder;
+use crate::{Repo, GitmapError, Oid, configure};
+use crate::annotations::NOTES_REF;
+
+/// Remote functions.
+impl Repo {
+
+    /// Clones the remote repository as a bare repository at the provided path.
+    /// HEAD points to the default branch of the remote.
+    pub fn clone<P: AsRef<Path>>(url: &str, path: P) -> Result<Self, GitmapError> {
+        Self::clone_with(url, path, RemoteCallbacks::new())
+    }
+
+    /// Clones the remote repository using the provided callbacks, which
+    /// typically supply credentials. Errors when the path already holds a
+    /// repository.
+    pub fn clone_with<P: AsRef<Path>>(url: &str, path: P, callbacks: RemoteCallbacks<'_>) -> Result<Self, GitmapError> {
+        let path = path.as_ref();
+        if Repository::open(path).is_ok() {
+            return Err(GitmapError::new(ErrorCode::Exists, ErrorClass::Repository, format!("repository already exists at `{}`", path.display())));
+        }
+        let mut opts = FetchOptions::new();
+        opts.remote_callbacks(callbacks);
+        let repo = RepoBuilder::new().bare(true).fetch_options(opts).clone(url, path)?;
+        configure(&repo)?;
+        Ok(Self::new(repo))
+    }
+
+    /// Adds a remote with the provided URL.
+    pub fn add_remote(&mut self, name: &str, url: &str) -> Result<(), GitmapError> {
+        self.repo.remote(name, url)?;
+        Ok(())
+    }
+
+    /// Removes the remote.
+    pub fn remove_remote(&mut self, name: &str) -> Result<(), GitmapError> {
+        Ok(self.repo.remote_delete(name)?)
+    }
+
+    /// List names of configured remotes.
+    pub fn remotes(&self) -> Vec<String> {
+        match self.repo.remotes() {
+            Ok(names) => names.iter().flatten().map(String::from).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// List remote-tracking branch names like `origin/main`, which fetches
+    /// update. `branches` only lists local branches.
+    pub fn remote_branches(&self) -> Vec<String> {
+        self.all_branches().into_iter()
+            .filter(|(_, kind)| *kind == BranchType::Remote)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// List local and remote-tracking branch names with their type.
+    pub fn all_branches(&self) -> Vec<(String, BranchType)> {
+        let branches = match self.repo.branches(None) {
+            Ok(branches) => branches,
+            Err(_) => return Vec::new(),
+        };
+        let mut names: Vec<(String, BranchType)> = branches
+            .filter_map(|item| item.ok())
+            .filter_map(|(branch, kind)| branch.name().ok().flatten().map(|name| (name.to_string(), kind)))
+            .filter(|(name, _)| !name.ends_with("/HEAD"))
+            .collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        names
+    }
+
+    /// Returns the name of the tracking branch of the working branch, like
+    /// `origin/main`, or `None` when no upstream is configured.
+    pub fn upstream(&self) -> Result<Option<String>, GitmapError> {
+        let branch = match self.branch() {
+            Some(branch) => self.repo.find_branch(&branch, BranchType::Local)?,
+            None => return Ok(None),
+        };
+        let upstream = match branch.upstream() {
+            Ok(upstream) => upstream,
+            Err(err) if err.code() == ErrorCode::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        match upstream.name()? {
+            Some(name) => Ok(Some(name.to_string())),
+            None => Err(GitmapError::InvalidUtf8("upstream branch name is not valid UTF-8".to_string())),
+        }
+    }
+
+    /// Sets the tracking branch of the local branch, like `origin/main`. An
+    /// empty upstream clears it. Errors when the branch does not exist.
+    pub fn set_upstream(&self, branch: &str, upstream: &str) -> Result<(), GitmapError> {
+        let mut branch = self.repo.find_branch(branch, BranchType::Local)?;
+        match upstream {
+            "" if branch.upstream().is_err() => {},
+            "" => branch.set_upstream(None)?,
+            upstream => branch.set_upstream(Some(upstream))?,
+        }
+        Ok(())
+    }
+
+    /// Pushes the branch, and commit annotations when present, to the remote.
+    pub fn push(&self, remote: &str, branch: &str) -> Result<(), GitmapError> {
+        self.push_with(remote, branch, RemoteCallbacks::new())
+    }
+
+    /// Pushes the branch using the provided callbacks, which typically supply
+    /// credentials. Errors when the remote rejects the update.
+    pub fn push_with(&self, remote: &str, branch: &str, callbacks: RemoteCallbacks<'_>) -> Result<(), GitmapError> {
+        let started = self.slow_op_start();
+        let result = self.push_unreported(remote, branch, callbacks);
+        self.report_slow_op(started, "push", Some(branch), 0);
+        result
+    }
+
+    /// Pushes the branch using the provided callbacks without reporting
+    /// slowness.
+    fn push_unreported(&self, remote: &str, branch: &str, mut callbacks: RemoteCallbacks<'_>) -> Result<(), GitmapError> {
+        let mut refspecs = vec![format!("refs/heads/{0}:refs/heads/{0}", branch)];
+        if self.repo.find_reference(NOTES_REF).is_ok() {
+            refspecs.push(format!("{0}:{0}", NOTES_REF));
+        }
+        let rejected = Rc::new(RefCell::new(None));
+        let sink = rejected.clone();
+        callbacks.push_update_reference(move |refname, status| {
+            if let Some(status) = status {
+                sink.borrow_mut().get_or_insert(format!("remote rejected `{}`: {}", refname, status));
+            }
+            Ok(())
+        });
+        let mut opts = PushOptions::new();
+        opts.remote_callbacks(callbacks);
+        self.repo.find_remote(remote)?.push(&refspecs, Some(&mut opts))?;
+        let rejected = rejected.borrow_mut().take();
+        match rejected {
+            Some(message) => Err(GitmapError::new(ErrorCode::NotFastForward, ErrorClass::Reference, message)),
+            None => Ok(()),
+        }
+    }
+
+    /// Fetches the branch, and commit annotations when present, from the
+    /// remote and fast-forwards the local branch.
+    pub fn fetch(&mut self, remote: &str, branch: &str) -> Result<(), GitmapError> {
+        self.fetch_with(remote, branch, RemoteCallbacks::new())
+    }
+
+    /// Fetches the branch using the provided callbacks, which typically supply
+    /// credentials. The local branch is created when missing and fast-forwarded
+    /// otherwise, erroring when histories diverged. Staged changes are
+    /// discarded when the working branch moves. Annotations are only updated
+    /// when they fast-forward.
+    pub fn fetch_with(&mut self, remote: &str, branch: &str, callbacks: RemoteCallbacks<'_>) -> Result<(), GitmapError> {
+        let started = self.slow_op_start();
+        let result = self.fetch_unreported(remote, branch, callbacks);
+        self.report_slow_op(started, "fetch", Some(branch), 0);
+        result
+    }
+
+    /// Fetches the branch using the provided callbacks without reporting
+    /// slowness.
+    fn fetch_unreported(&mut self, remote: &str, branch: &str, callbacks: RemoteCallbacks<'_>) -> Result<(), GitmapError> {
+        let tracking = format!("refs/remotes/{}/{}", remote, branch);
+        let notes = format!("refs/notes/remotes/{}/gitmap", remote);
+        let refspecs = [
+            format!("+refs/heads/{}:{}", branch, tracking),
+            format!("+{}:{}", NOTES_REF, notes),
+        ];
+        let mut opts = FetchOptions::new();
+        opts.remote_callbacks(callbacks);
+        self.repo.find_remote(remote)?.fetch(&refspecs, Some(&mut opts), None)?;
+        if let Ok(id) = self.repo.refname_to_id(&notes) {
+            let _ = self.fast_forward(NOTES_REF, id);
+        }
+        let id = self.repo.refname_to_id(&tracking)?;
+        let refname = format!("refs/heads/{}", branch);
+        if self.fast_forward(&refname, id)? && self.branch().as_deref() == Some(branch) {
+            self.set_staged_tree(None);
+        }
+        Ok(())
+    }
+
+    /// Moves the reference to the commit when this is a fast-forward, creating
+    /// it when missing. Returns true if the reference moved.
+    fn fast_forward(&self, refname: &str, id: Oid) -> Result<bool, GitmapError> {
+        let mut reference = match self.repo.find_reference(refname) {
+            Ok(reference) => reference,
+            Err(_) => {
+                self.repo.reference(refname, id, false, "fetch: created")?;
+                return Ok(true);
+            },
+        };
+        let current = reference.peel_to_commit()?.id();
+        if current == id || self.repo.graph_descendant_of(current, id)? {
+            Ok(false)
+        } else if self.repo.graph_descendant_of(id, current)? {
+            reference.set_target(id, "fetch: fast-forward")?;
+            Ok(true)
+        } else {
+            Err(GitmapError::new(ErrorCode::NotFastForward, ErrorClass::Reference, format!("`{}` has diverged", refname)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use tempfile::TempDir;
+    use super::*;
+
+    /// Returns the URL of the repository at the provided path.
+    fn url(path: &Path) -> String {
+        format!("file://{}", path.display())
+    }
+
+    #[test]
+    fn clones_repository() {
+        let remote_path = TempDir::new().unwrap().path().to_owned();
+        let mut remote = Repo::init(&remote_path).unwrap();
+        remote.insert_key("foo", "1".as_bytes()).unwrap();
+        remote.commit("").unwrap();
+        remote.switch_branch("main").unwrap();
+        remote.insert_key("bar", "2".as_bytes()).unwrap();
+        remote.commit("").unwrap();
+        let path = TempDir::new().unwrap().path().to_owned();
+        let repo = Repo::clone(&url(&remote_path), &path).unwrap();
+        assert_eq!(repo.branch().unwrap(), "main");
+        assert_eq!(repo.keys(), ["bar", "foo"]);
+        assert_eq!(repo.key("bar").unwrap(), "2".as_bytes());
+        assert_eq!(repo.remotes(), ["origin"]);
+        assert_eq!(repo.has_branch("main"), true);
+        assert_eq!(Repo::clone(&url(&remote_path), &path).is_err(), true);
+        assert_eq!(Repo::clone(&url(&path.join("missing")), TempDir::new().unwrap().path()).is_err(), true);
+    }
+
+    #[test]
+    fn provides_upstream() {
+        let remote_path = TempDir::new().unwrap().path().to_owned();
+        let mut remote = Repo::init(&remote_path).unwrap();
+        remote.insert_key("foo", "1".as_bytes()).unwrap();
+        remote.commit("").unwrap();
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::clone(&url(&remote_path), &path).unwrap();
+        assert_eq!(repo.upstream().unwrap(), Some("origin/master".to_string()));
+        repo.switch_branch("other").unwrap();
+        assert_eq!(repo.upstream().unwrap(), None);
+        let path = TempDir::new().unwrap().path().to_owned();
+        let repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.upstream().unwrap(), None);
+    }
+
+    #[test]
+    fn sets_upstream() {
+        let remote_path = TempDir::new().unwrap().path().to_owned();
+        let mut remote = Repo::init(&remote_path).unwrap();
+        remote.insert_key("foo", "1".as_bytes()).unwrap();
+        remote.commit("").unwrap();
+        remote.switch_branch("main").unwrap();
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::clone(&url(&remote_path), &path).unwrap();
+        repo.switch_branch("master").unwrap();
+        repo.set_upstream("master", "origin/main").unwrap();
+        assert_eq!(repo.upstream().unwrap(), Some("origin/main".to_string()));
+        repo.set_upstream("master", "").unwrap();
+        assert_eq!(repo.upstream().unwrap(), None);
+        repo.set_upstream("master", "").unwrap();
+        assert_eq!(repo.set_upstream("master", "origin/missing").is_err(), true);
+        assert_eq!(matches!(repo.set_upstream("missing", "origin/main"), Err(GitmapError::NotFound(_))), true);
+    }
+
+    #[test]
+    fn manages_remotes() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        assert_eq!(repo.remotes(), Vec::<String>::new());
+        repo.add_remote("origin", "file:///tmp/origin").unwrap();
+        repo.add_remote("backup", "file:///tmp/backup").unwrap();
+        assert_eq!(repo.add_remote("origin", "file:///tmp/other").is_err(), true);
+        assert_eq!(repo.remotes(), ["backup", "origin"]);
+        repo.remove_remote("backup").unwrap();
+        assert_eq!(repo.remotes(), ["origin"]);
+        assert_eq!(repo.remove_remote("backup").is_err(), true);
+    }
+
+    #[test]
+    fn lists_remote_branches() {
+        let remote_path = TempDir::new().unwrap().path().to_owned();
+        let mut remote = Repo::init(&remote_path).unwrap();
+        remote.commit("").unwrap();
+        remote.switch_branch("staging").unwrap();
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.add_remote("origin", &url(&remote_path)).unwrap();
+        assert_eq!(repo.remote_branches(), Vec::<String>::new());
+        repo.fetch("origin", "staging").unwrap();
+        repo.fetch("origin", "master").unwrap();
+        assert_eq!(repo.branches(), ["master", "staging"]);
+        assert_eq!(repo.remote_branches(), ["origin/master", "origin/staging"]);
+        assert_eq!(repo.all_branches(), [
+            ("master".to_string(), BranchType::Local),
+            ("origin/master".to_string(), BranchType::Remote),
+            ("origin/staging".to_string(), BranchType::Remote),
+            ("staging".to_string(), BranchType::Local),
+        ]);
+    }
+
+    #[test]
+    fn pushes_and_fetches() {
+        let remote_path = TempDir::new().unwrap().path().to_owned();
+        Repo::init(&remote_path).unwrap();
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.add_remote("origin", &url(&remote_path)).unwrap();
+        repo.insert_key("foo", "0".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.push("origin", "master").unwrap();
+
+        let other_path = TempDir::new().unwrap().path().to_owned();
+        let mut other = Repo::init(&other_path).unwrap();
+        other.add_remote("origin", &url(&remote_path)).unwrap();
+        other.fetch("origin", "master").unwrap(); // no annotations yet
+        assert_eq!(other.key("foo").unwrap(), "0".as_bytes());
+
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        let annotated = repo.commit_with_annotations("", &[("deploy", "1".as_bytes())]).unwrap();
+        repo.push("origin", "master").unwrap();
+        assert_eq!(Repo::read_key(&remote_path, Some("master"), "foo").unwrap().unwrap(), "1".as_bytes());
+        other.fetch("origin", "master").unwrap();
+        assert_eq!(other.key("foo").unwrap(), "1".as_bytes());
+        assert_eq!(other.commit_annotations(annotated).unwrap().len(), 1);
+
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.push("origin", "master").unwrap();
+        other.insert_key("staged", "".as_bytes()).unwrap();
+        other.fetch("origin", "master").unwrap();
+        assert_eq!(other.key("foo").unwrap(), "2".as_bytes());
+        assert_eq!(other.has_key("staged"), false);
+
+        other.insert_key("foo", "3".as_bytes()).unwrap();
+        other.commit("").unwrap();
+        repo.insert_key("foo", "4".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.push("origin", "master").unwrap();
+        assert_eq!(other.push("origin", "master").is_err(), true); // not a fast-forward
+        assert_eq!(other.fetch("origin", "master").is_err(), true); // diverged
+        assert_eq!(other.key("foo").unwrap(), "3".as_bytes());
+    }
+}
@@ -0,0 +1,122 @@
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+use crate::Repo;
+
+/// Threshold and sink of slow operation reports.
+pub(crate) type SlowOpSink = (Duration, RefCell<Box<dyn FnMut(SlowOp) + Send>>);
+
+/// Operation which took longer than the threshold set with
+/// `set_slow_op_threshold`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowOp {
+    /// Name of the operation like `commit` or `insert_key`.
+    pub name: &'static str,
+    /// Wall-clock duration of the operation.
+    pub duration: Duration,
+    /// Key or branch the operation worked on if any.
+    pub key_hint: Option<String>,
+    /// Number of value bytes read or written.
+    pub bytes: usize,
+}
+
+/// Slow operation functions.
+impl Repo {
+
+    /// Reports `key`, `insert_key`, `insert_key_from`, `remove_key`, `keys`,
+    /// `commit`, `fetch` and `push` calls which take longer than the threshold
+    /// to the sink. Other operations are not instrumented. The sink runs on
+    /// the calling thread right after the operation and a panic in it is
+    /// caught. It moves with the repository so it has to be `Send`.
+    pub fn set_slow_op_threshold<F>(&mut self, threshold: Duration, sink: F)
+    where
+        F: FnMut(SlowOp) + Send + 'static,
+    {
+        self.slow_ops = Some((threshold, RefCell::new(Box::new(sink))));
+    }
+
+    /// Stops reporting slow operations.
+    pub fn clear_slow_op_threshold(&mut self) {
+        self.slow_ops = None;
+    }
+
+    /// Returns the start time of an operation when reporting is enabled.
+    pub(crate) fn slow_op_start(&self) -> Option<Instant> {
+        self.slow_ops.as_ref().map(|_| Instant::now())
+    }
+
+    /// Reports the operation started at the provided time when it exceeded
+    /// the threshold.
+    pub(crate) fn report_slow_op(&self, started: Option<Instant>, name: &'static str, key_hint: Option<&str>, bytes: usize) {
+        let ((threshold, sink), duration) = match (&self.slow_ops, started) {
+            (Some(slow_ops), Some(started)) => (slow_ops, started.elapsed()),
+            _ => return,
+        };
+        if duration <= *threshold {
+            return;
+        }
+        let op = SlowOp { name, duration, key_hint: key_hint.map(String::from), bytes };
+        if let Ok(mut report) = sink.try_borrow_mut() {
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| report(op)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn reports_slow_operations() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        repo.set_slow_op_threshold(Duration::from_secs(0), move |op| sink.lock().unwrap().push(op));
+        repo.insert_key("foo", "123".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.key("foo").unwrap();
+        let ops: Vec<(&str, Option<String>, usize)> = reports.lock().unwrap().iter().map(|op| (op.name, op.key_hint.clone(), op.bytes)).collect();
+        assert_eq!(ops, [
+            ("insert_key", Some("foo".to_string()), 3),
+            ("commit", None, 0),
+            ("key", Some("foo".to_string()), 3),
+        ]);
+        repo.set_slow_op_threshold(Duration::from_secs(3600), |_| panic!("not slow"));
+        repo.insert_key("bar", "".as_bytes()).unwrap();
+        repo.clear_slow_op_threshold();
+        repo.insert_key("baz", "".as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn reports_after_moving_repository() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let sink = names.clone();
+        repo.set_slow_op_threshold(Duration::from_secs(0), move |op| sink.lock().unwrap().push(op.name));
+        repo.insert_key_from("foo", "123".as_bytes()).unwrap();
+        let repo = std::thread::spawn(move || {
+            repo.insert_key("bar", "1".as_bytes()).unwrap();
+            repo
+        }).join().unwrap();
+        repo.key("bar").unwrap();
+        assert_eq!(*names.lock().unwrap(), ["insert_key_from", "insert_key", "key"]);
+        drop(repo);
+        assert_eq!(Arc::strong_count(&names), 1);
+    }
+
+    #[test]
+    fn isolates_panicking_sink() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.set_slow_op_threshold(Duration::from_secs(0), |op| panic!("sink failed on {}", op.name));
+        repo.insert_key("foo", "1".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        repo.insert_key("foo", "2".as_bytes()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.key("foo").unwrap(), "2".as_bytes());
+    }
+}
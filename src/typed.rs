@@ -0,0 +1,138 @@
+use std::error::Error;
+use std::fmt;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::{Repo, GitmapError};
+
+/// Error returned by typed key functions.
+#[derive(Debug)]
+pub enum KeyError {
+    /// Repository operation failed.
+    Repo(GitmapError),
+    /// Value could not be serialized.
+    Serialize(Box<dyn Error + Send + Sync>),
+    /// Stored value could not be deserialized into the requested type.
+    Deserialize(Box<dyn Error + Send + Sync>),
+}
+
+/// Typed key functions.
+impl Repo {
+
+    /// Stages the value serialized as JSON.
+    pub fn insert_json<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) -> Result<(), KeyError> {
+        let data = serde_json::to_vec(value).map_err(|err| KeyError::Serialize(err.into()))?;
+        Ok(self.insert_key(name, &data)?)
+    }
+
+    /// Retrieves key content deserialized from JSON. Returns `None` when the
+    /// key is missing and `KeyError::Deserialize` when the value does not
+    /// match the type.
+    pub fn key_json<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, KeyError> {
+        match self.try_key(name)? {
+            Some(data) => serde_json::from_slice(&data).map(Some).map_err(|err| KeyError::Deserialize(err.into())),
+            None => Ok(None),
+        }
+    }
+
+    /// Stages the value serialized with the compact bincode format.
+    #[cfg(feature = "bincode")]
+    pub fn insert_bin<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) -> Result<(), KeyError> {
+        let data = bincode::serialize(value).map_err(|err| KeyError::Serialize(err))?;
+        Ok(self.insert_key(name, &data)?)
+    }
+
+    /// Retrieves key content deserialized from bincode. Returns `None` when
+    /// the key is missing and `KeyError::Deserialize` when the value does not
+    /// match the type.
+    #[cfg(feature = "bincode")]
+    pub fn key_bin<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, KeyError> {
+        match self.try_key(name)? {
+            Some(data) => bincode::deserialize(&data).map(Some).map_err(|err| KeyError::Deserialize(err)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl fmt::Display for KeyError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Repo(err) => err.fmt(f),
+            Self::Serialize(err) => write!(f, "failed to serialize value: {}", err),
+            Self::Deserialize(err) => write!(f, "failed to deserialize value: {}", err),
+        }
+    }
+}
+
+impl Error for KeyError {
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Repo(err) => Some(err),
+            Self::Serialize(err) | Self::Deserialize(err) => Some(err.as_ref()),
+        }
+    }
+}
+
+impl From<GitmapError> for KeyError {
+
+    fn from(err: GitmapError) -> Self {
+        Self::Repo(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use serde::Deserialize;
+    use tempfile::TempDir;
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Config {
+        name: String,
+        limits: BTreeMap<String, u32>,
+        owner: Option<Owner>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Owner {
+        email: String,
+        teams: Vec<String>,
+    }
+
+    /// Returns a config with nested fields.
+    fn config() -> Config {
+        Config {
+            name: "app".to_string(),
+            limits: vec![("cpu".to_string(), 2), ("memory".to_string(), 512)].into_iter().collect(),
+            owner: Some(Owner { email: "ops@example.com".to_string(), teams: vec!["ops".to_string()] }),
+        }
+    }
+
+    #[test]
+    fn round_trips_json() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_json("config", &config()).unwrap();
+        repo.commit("").unwrap();
+        assert_eq!(repo.key_json::<Config>("config").unwrap(), Some(config()));
+        assert_eq!(repo.key_json::<Config>("missing").unwrap(), None);
+        repo.insert_key("broken", "{\"name\":".as_bytes()).unwrap();
+        assert_eq!(matches!(repo.key_json::<Config>("broken"), Err(KeyError::Deserialize(_))), true);
+        assert_eq!(matches!(repo.key_json::<Vec<u32>>("config"), Err(KeyError::Deserialize(_))), true);
+        assert_eq!(matches!(repo.insert_json(".gitmap.aliases", &1), Err(KeyError::Repo(_))), true);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn round_trips_bincode() {
+        let path = TempDir::new().unwrap().path().to_owned();
+        let mut repo = Repo::init(&path).unwrap();
+        repo.insert_bin("config", &config()).unwrap();
+        assert_eq!(repo.key_bin::<Config>("config").unwrap(), Some(config()));
+        assert_eq!(repo.key_bin::<Config>("missing").unwrap(), None);
+        repo.insert_key("broken", &[1, 2, 3]).unwrap();
+        assert_eq!(matches!(repo.key_bin::<Config>("broken"), Err(KeyError::Deserialize(_))), true);
+    }
+}
@@ -0,0 +1,67 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use gitmap::{Repo, Signature};
+use tempfile::{TempDir};
+
+/// Allocator tracking the current and the peak number of allocated bytes.
+struct CountingAllocator;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        PEAK.fetch_max(current, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the peak number of bytes allocated by `f` on top of the bytes
+/// allocated before the call.
+fn peak_growth<F: FnOnce()>(f: F) -> usize {
+    let base = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(base, Ordering::SeqCst);
+    f();
+    PEAK.load(Ordering::SeqCst) - base
+}
+
+const KEYS: usize = 50_000;
+
+#[test]
+fn keeps_memory_bounded_on_whole_tree_operations() {
+    let path = TempDir::new().unwrap().path().to_owned();
+    let mut repo = Repo::init(&path).unwrap();
+    repo.insert_key("seed", "".as_bytes()).unwrap();
+    repo.alias_key("alias", "seed").unwrap();
+    repo.commit("").unwrap();
+    repo.with_repository(|r| {
+        let head = r.head().unwrap().peel_to_commit().unwrap();
+        let blob = r.blob("value".as_bytes()).unwrap();
+        let mut builder = r.treebuilder(Some(&head.tree().unwrap())).unwrap();
+        for i in 0..KEYS {
+            builder.insert(format!("key{:06}", i), blob, 0o100644).unwrap();
+        }
+        let tree = r.find_tree(builder.write().unwrap()).unwrap();
+        let sig = Signature::now("gitmap", "gitmap@localhost").unwrap();
+        r.commit(Some("HEAD"), &sig, &sig, "large", &tree, &[&head]).unwrap();
+    });
+
+    let mut count = 0;
+    let growth = peak_growth(|| count = repo.len());
+    assert_eq!(count, KEYS + 1);
+    assert!(growth < 64 * 1024, "len allocated {} bytes", growth);
+
+    let growth = peak_growth(|| repo.remove().unwrap());
+    assert!(growth < 64 * 1024, "remove allocated {} bytes", growth);
+    assert_eq!(repo.len(), 0);
+    assert_eq!(repo.aliases().len(), 0);
+}
@@ -0,0 +1,100 @@
+use gitmap::{BulkLoader, BulkOptions, KeyChange, Repo};
+#[cfg(feature = "csv")]
+use gitmap::{ImportPolicy};
+use tempfile::{TempDir};
+
+/// Writes an empty value through every insert variant, committing the batch
+/// and bulk loads, and returns the keys.
+fn insert_empty_values(repo: &mut Repo) -> Vec<String> {
+    repo.insert_key("insert", "".as_bytes()).unwrap();
+    repo.insert_str("str", "").unwrap();
+    repo.insert_keys(vec![("keys".to_string(), Vec::new())]).unwrap();
+    repo.insert_key_from("reader", "".as_bytes()).unwrap();
+    assert_eq!(repo.insert_key_if("if", None, "".as_bytes()).unwrap(), true);
+    let slot = repo.insert_rotating("ring.", "".as_bytes(), 2).unwrap();
+    let mut batch = repo.batch();
+    batch.insert("batch", "".as_bytes()).unwrap();
+    batch.commit("").unwrap();
+    let mut loader = BulkLoader::new(repo, BulkOptions { commit_every: 0, message_template: String::new() });
+    loader.push("bulk", "".as_bytes()).unwrap();
+    loader.finish().unwrap();
+    ["insert", "str", "keys", "reader", "if", &slot, "batch", "bulk"].iter().map(|key| key.to_string()).collect()
+}
+
+#[test]
+fn distinguishes_empty_values_from_missing_keys() {
+    let path = TempDir::new().unwrap().path().to_owned();
+    let mut repo = Repo::init(&path).unwrap();
+    let keys = insert_empty_values(&mut repo);
+    for key in &keys {
+        assert_eq!(repo.has_key(key), true);
+        assert_eq!(repo.key(key), Some(Vec::new()));
+        assert_eq!(repo.staged_key(key), Some(Vec::new()));
+        assert_eq!(repo.key_size(key), Some(0));
+    }
+    repo.alias_key("alias", "insert").unwrap();
+    assert_eq!(repo.key("alias"), Some(Vec::new()));
+    assert_eq!(repo.key_size("alias"), Some(0));
+    assert_eq!(repo.key("missing"), None);
+    assert_eq!(repo.key_size("missing"), None);
+    assert_eq!(repo.has_key("missing"), false);
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(repo.keys(), sorted);
+    assert_eq!(repo.len(), keys.len());
+}
+
+#[test]
+fn keeps_empty_values_across_commits() {
+    let path = TempDir::new().unwrap().path().to_owned();
+    let mut repo = Repo::init(&path).unwrap();
+    insert_empty_values(&mut repo);
+    repo.commit("").unwrap();
+    Repo::write_key(&path, None, "write", "".as_bytes(), "").unwrap();
+    let repo = Repo::open(&path).unwrap();
+    assert_eq!(repo.has_key("write"), true);
+    assert_eq!(repo.len_committed(), 9);
+    assert_eq!(repo.key("write"), Some(Vec::new()));
+    assert_eq!(Repo::read_key(&path, None, "write").unwrap(), Some(Vec::new()));
+    assert_eq!(Repo::read_key(&path, None, "insert").unwrap(), Some(Vec::new()));
+    assert_eq!(Repo::read_key(&path, None, "missing").unwrap(), None);
+}
+
+#[test]
+fn tracks_changes_between_empty_and_missing() {
+    let path = TempDir::new().unwrap().path().to_owned();
+    let mut repo = Repo::init(&path).unwrap();
+    repo.insert_key("foo", "".as_bytes()).unwrap();
+    assert_eq!(repo.key_changed("foo"), true); // missing -> empty
+    assert_eq!(repo.staged_changes().unwrap(), [KeyChange::Added("foo".to_string())]);
+    repo.commit("added").unwrap();
+    repo.remove_key("foo").unwrap();
+    assert_eq!(repo.key_changed("foo"), true); // empty -> missing
+    assert_eq!(repo.staged_changes().unwrap(), [KeyChange::Removed("foo".to_string())]);
+    repo.commit("removed").unwrap();
+    let diff = repo.diff_keys("HEAD~1", "HEAD").unwrap();
+    assert_eq!((diff.added.len(), diff.removed, diff.modified.len()), (0, vec!["foo".to_string()], 0));
+    let diff = repo.diff_keys("HEAD", "HEAD~1").unwrap();
+    assert_eq!((diff.added, diff.removed.len(), diff.modified.len()), (vec!["foo".to_string()], 0, 0));
+    repo.insert_key("foo", "".as_bytes()).unwrap();
+    repo.commit("restored").unwrap();
+    let log = repo.log_key("foo");
+    assert_eq!(log.iter().map(|c| c.message.as_str()).collect::<Vec<_>>(), ["restored", "removed", "added"]);
+    repo.insert_key("foo", "".as_bytes()).unwrap();
+    assert_eq!(repo.changed(), false); // empty -> empty
+}
+
+#[cfg(feature = "csv")]
+#[test]
+fn exports_and_imports_empty_values() {
+    let path = TempDir::new().unwrap().path().to_owned();
+    let mut repo = Repo::init(&path).unwrap();
+    repo.insert_key("foo", "".as_bytes()).unwrap();
+    let mut data = Vec::new();
+    repo.export_csv(&mut data).unwrap();
+    let path = TempDir::new().unwrap().path().to_owned();
+    let mut copy = Repo::init(&path).unwrap();
+    copy.import_csv(data.as_slice(), ImportPolicy::FailOnConflict).unwrap();
+    assert_eq!(copy.keys(), ["foo"]);
+    assert_eq!(copy.key("foo"), Some(Vec::new()));
+}